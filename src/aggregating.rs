@@ -0,0 +1,469 @@
+use super::{Estimate, EstimationRequest, GasPrice, GasPrice1559, GasPriceEstimating};
+use crate::{
+    gas_price::mul_f64_floor,
+    priority::{Answer, NamedEstimator},
+    Error, Result, U256,
+};
+use futures::future::join_all;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// How the fee fields of the individual estimates are combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    Median,
+    /// Mean weighted by the weights set through `AggregatingGasPriceEstimating::with_weights`.
+    WeightedMean,
+    Max,
+    Min,
+}
+
+#[derive(Clone, Debug)]
+pub struct Params {
+    pub policy: Policy,
+    // minimum number of estimates that have to remain after discarding failures and outliers
+    pub quorum: usize,
+    // estimates whose effective gas price deviates from the median by more than this fraction of
+    // the median are discarded as outliers
+    pub max_deviation: f64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            policy: Policy::Median,
+            quorum: 2,
+            max_deviation: 0.5,
+        }
+    }
+}
+
+// Queries all estimators concurrently and combines their estimates field by field. The latency and
+// result of every estimator are recorded in the metrics under its name.
+//
+// If any estimator answered with a legacy gas price the result is a legacy gas price combined from
+// the effective gas prices. Otherwise it is an EIP-1559 gas price which includes a max fee per blob
+// gas if all estimates have one.
+pub struct AggregatingGasPriceEstimating {
+    estimators: Vec<NamedEstimator>,
+    weights: Vec<f64>,
+    params: Params,
+}
+
+impl AggregatingGasPriceEstimating {
+    /// Estimators are named by their index in logs.
+    pub fn new(estimators: Vec<Box<dyn GasPriceEstimating>>, params: Params) -> Self {
+        let estimators = estimators
+            .into_iter()
+            .enumerate()
+            .map(|(i, estimator)| NamedEstimator::new(i.to_string(), estimator))
+            .collect();
+        Self::named(estimators, params)
+    }
+
+    /// Estimators are identified by their name and labels in logs.
+    pub fn named(estimators: Vec<NamedEstimator>, params: Params) -> Self {
+        let weights = vec![1.0; estimators.len()];
+        Self {
+            estimators,
+            weights,
+            params,
+        }
+    }
+
+    /// Sets the weights of the estimators in order.
+    ///
+    /// Fails with `Error::Validation` if the number of weights differs from the number of
+    /// estimators or a weight is not a positive finite number.
+    pub fn with_weights(self, weights: Vec<f64>) -> Result<Self> {
+        if weights.len() != self.estimators.len() {
+            return Err(Error::Validation(format!(
+                "{} weights for {} estimators",
+                weights.len(),
+                self.estimators.len()
+            )));
+        }
+        if !weights.iter().all(|w| w.is_finite() && *w > 0.0) {
+            return Err(Error::validation("weights must be positive"));
+        }
+        Ok(Self { weights, ..self })
+    }
+
+    async fn query<'a, T, F, R>(&'a self, operation: T) -> Vec<Result<R>>
+    where
+        T: Fn(&'a dyn GasPriceEstimating) -> F,
+        F: Future<Output = Result<R>>,
+        R: Answer,
+    {
+        join_all(self.estimators.iter().map(|estimator| {
+            let future = operation(estimator.estimator.as_ref());
+            async move {
+                let start = Instant::now();
+                let result = future.await;
+                crate::metrics::estimate(
+                    &estimator.name,
+                    start.elapsed(),
+                    result.as_ref().ok().map(Answer::gas_price).as_ref(),
+                );
+                result
+            }
+        }))
+        .await
+    }
+
+    async fn aggregate<'a, T, F>(&'a self, operation: T) -> Result<GasPrice>
+    where
+        T: Fn(&'a dyn GasPriceEstimating) -> F,
+        F: Future<Output = Result<GasPrice>>,
+    {
+        let (gas_price, _) = self.combine(self.query(operation).await)?;
+        Ok(gas_price)
+    }
+
+    // Returns the combined gas price and the indices of the estimators it is based on.
+    fn combine(&self, results: Vec<Result<GasPrice>>) -> Result<(GasPrice, Vec<usize>)> {
+        let mut estimates = Vec::new();
+        let mut errors = Vec::new();
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(gas_price) => estimates.push((i, gas_price)),
+                Err(err) => {
+                    let NamedEstimator { name, labels, .. } = &self.estimators[i];
+                    tracing::warn!(estimator = %name, ?labels, ?err, "gas estimator failed");
+                    errors.push(err);
+                }
+            }
+        }
+        if estimates.is_empty() {
+            return Err(Error::AllFailed(errors));
+        }
+
+        let mut effective_gas_prices = estimates
+            .iter()
+            .map(|(_, gas_price)| gas_price.effective_gas_price())
+            .collect::<Vec<_>>();
+        let consensus = median(&mut effective_gas_prices);
+        let max_deviation = mul_f64_floor(consensus, self.params.max_deviation);
+        estimates.retain(|(i, gas_price)| {
+            let effective_gas_price = gas_price.effective_gas_price();
+            let deviation = if effective_gas_price > consensus {
+                effective_gas_price - consensus
+            } else {
+                consensus - effective_gas_price
+            };
+            if deviation > max_deviation {
+                let NamedEstimator { name, labels, .. } = &self.estimators[*i];
+                tracing::warn!(
+                    estimator = %name,
+                    ?labels,
+                    %gas_price,
+                    %consensus,
+                    "gas estimator is an outlier"
+                );
+                return false;
+            }
+            true
+        });
+        if estimates.len() < self.params.quorum {
+            return Err(Error::NoQuorum {
+                available: estimates.len(),
+                required: self.params.quorum,
+            });
+        }
+
+        let weights = estimates
+            .iter()
+            .map(|(i, _)| self.weights[*i])
+            .collect::<Vec<_>>();
+        let field = |value: fn(&GasPrice) -> U256| {
+            let mut values = estimates
+                .iter()
+                .map(|(_, gas_price)| value(gas_price))
+                .collect::<Vec<_>>();
+            self.params.policy.combine(&mut values, &weights)
+        };
+        let gas_price = if estimates
+            .iter()
+            .any(|(_, gas_price)| matches!(gas_price, GasPrice::Legacy { .. }))
+        {
+            GasPrice::Legacy {
+                gas_price: field(GasPrice::effective_gas_price),
+            }
+        } else {
+            let fee = GasPrice1559 {
+                base_fee_per_gas: field(|gas_price| gas_price.to_eip1559().base_fee_per_gas),
+                max_fee_per_gas: field(|gas_price| gas_price.to_eip1559().max_fee_per_gas),
+                max_priority_fee_per_gas: field(|gas_price| {
+                    gas_price.to_eip1559().max_priority_fee_per_gas
+                }),
+            };
+            if estimates
+                .iter()
+                .all(|(_, gas_price)| gas_price.max_fee_per_blob_gas().is_some())
+            {
+                GasPrice::from(fee).with_blob_fee(field(|gas_price| {
+                    gas_price.max_fee_per_blob_gas().unwrap_or_default()
+                }))
+            } else {
+                fee.into()
+            }
+        };
+        Ok((
+            gas_price.validate()?,
+            estimates.into_iter().map(|(i, _)| i).collect(),
+        ))
+    }
+}
+
+impl Policy {
+    fn combine(self, values: &mut [U256], weights: &[f64]) -> U256 {
+        match self {
+            Policy::Median => median(values),
+            Policy::WeightedMean => {
+                let total = weights.iter().sum::<f64>();
+                values
+                    .iter()
+                    .zip(weights)
+                    .fold(U256::zero(), |sum, (value, weight)| {
+                        sum.saturating_add(mul_f64_floor(*value, weight / total))
+                    })
+            }
+            Policy::Max => values.iter().copied().max().unwrap_or_default(),
+            Policy::Min => values.iter().copied().min().unwrap_or_default(),
+        }
+    }
+}
+
+// Median of the values, the mean of the two middle values for an even number of values.
+fn median(values: &mut [U256]) -> U256 {
+    if values.is_empty() {
+        return U256::zero();
+    }
+    values.sort_unstable();
+    let middle = values.len() / 2;
+    if values.len() % 2 == 1 {
+        values[middle]
+    } else {
+        let (low, high) = (values[middle - 1], values[middle]);
+        low + (high - low) / 2
+    }
+}
+
+#[async_trait::async_trait]
+impl GasPriceEstimating for AggregatingGasPriceEstimating {
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
+        self.aggregate(|estimator| estimator.estimate_with_limits(gas_limit, time_limit))
+            .await
+    }
+
+    async fn estimate(&self) -> Result<GasPrice> {
+        self.aggregate(|estimator| estimator.estimate()).await
+    }
+
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        self.aggregate(|estimator| estimator.estimate_with_request(request))
+            .await
+    }
+
+    // The block number is the latest and the age the oldest of the combined estimates.
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        let (gas_prices, estimates): (Vec<_>, Vec<_>) = self
+            .query(|estimator| estimator.estimate_detailed(request))
+            .await
+            .into_iter()
+            .map(|result| match result {
+                Ok(estimate) => (Ok(estimate.gas_price), Some(estimate)),
+                Err(err) => (Err(err), None),
+            })
+            .unzip();
+        let (gas_price, used) = self.combine(gas_prices)?;
+        let used = used
+            .into_iter()
+            .filter_map(|i| estimates[i].as_ref())
+            .collect::<Vec<_>>();
+        let sources = used
+            .iter()
+            .map(|estimate| estimate.source.as_str())
+            .collect::<Vec<_>>();
+        let mut estimate = Estimate::new(gas_price, format!("aggregate({})", sources.join(", ")));
+        estimate.block_number = used.iter().filter_map(|e| e.block_number).max();
+        estimate.age = used.iter().filter_map(|e| e.age).max();
+        Ok(estimate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MockGasPriceEstimating;
+    use super::*;
+    use futures::future::FutureExt;
+
+    fn estimator(result: Result<GasPrice>) -> Box<dyn GasPriceEstimating> {
+        let mut estimator = MockGasPriceEstimating::new();
+        let mut result = Some(result);
+        estimator
+            .expect_estimate()
+            .times(1)
+            .returning(move || result.take().unwrap());
+        Box::new(estimator)
+    }
+
+    fn eip1559(base_fee: u64, max_fee: u64, priority_fee: u64) -> GasPrice {
+        GasPrice1559 {
+            base_fee_per_gas: base_fee.into(),
+            max_fee_per_gas: max_fee.into(),
+            max_priority_fee_per_gas: priority_fee.into(),
+        }
+        .into()
+    }
+
+    fn params(policy: Policy, quorum: usize) -> Params {
+        Params {
+            policy,
+            quorum,
+            max_deviation: 0.5,
+        }
+    }
+
+    #[test]
+    fn median_per_field() {
+        let aggregating = AggregatingGasPriceEstimating::new(
+            vec![
+                estimator(Ok(eip1559(100, 200, 2))),
+                estimator(Ok(eip1559(110, 180, 3))),
+                estimator(Ok(eip1559(90, 220, 1))),
+            ],
+            params(Policy::Median, 3),
+        );
+        let gas_price = aggregating.estimate().now_or_never().unwrap().unwrap();
+        assert_eq!(gas_price, eip1559(100, 200, 2));
+    }
+
+    #[test]
+    fn policies() {
+        let values = [U256::from(10), U256::from(40), U256::from(20)];
+        let combine =
+            |policy: Policy, weights: &[f64]| policy.combine(&mut values.clone(), weights);
+        assert_eq!(combine(Policy::Median, &[1.0; 3]), 20.into());
+        assert_eq!(combine(Policy::Max, &[1.0; 3]), 40.into());
+        assert_eq!(combine(Policy::Min, &[1.0; 3]), 10.into());
+        assert_eq!(combine(Policy::WeightedMean, &[2.0, 1.0, 1.0]), 20.into());
+        assert_eq!(median(&mut [10.into(), 13.into()]), 11.into());
+    }
+
+    #[test]
+    fn weighted_mean() {
+        let aggregating = AggregatingGasPriceEstimating::new(
+            vec![
+                estimator(Ok(eip1559(100, 200, 4))),
+                estimator(Ok(eip1559(140, 240, 8))),
+            ],
+            params(Policy::WeightedMean, 2),
+        )
+        .with_weights(vec![3.0, 1.0])
+        .unwrap();
+        let gas_price = aggregating.estimate().now_or_never().unwrap().unwrap();
+        assert_eq!(gas_price, eip1559(110, 210, 5));
+    }
+
+    #[test]
+    fn rejects_invalid_weights() {
+        for weights in [vec![1.0], vec![1.0, 0.0], vec![1.0, f64::NAN]] {
+            let result = AggregatingGasPriceEstimating::new(
+                vec![
+                    Box::new(MockGasPriceEstimating::new()),
+                    Box::new(MockGasPriceEstimating::new()),
+                ],
+                params(Policy::WeightedMean, 2),
+            )
+            .with_weights(weights);
+            assert!(matches!(result, Err(Error::Validation(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_outliers() {
+        let aggregating = AggregatingGasPriceEstimating::new(
+            vec![
+                estimator(Ok(eip1559(100, 200, 2))),
+                estimator(Ok(eip1559(100_000, 200_000, 2_000))),
+                estimator(Ok(eip1559(110, 180, 4))),
+            ],
+            params(Policy::Max, 2),
+        );
+        let gas_price = aggregating.estimate().now_or_never().unwrap().unwrap();
+        assert_eq!(gas_price, eip1559(110, 200, 4));
+    }
+
+    #[test]
+    fn requires_quorum() {
+        let aggregating = AggregatingGasPriceEstimating::named(
+            vec![
+                NamedEstimator::new("node", estimator(Ok(eip1559(100, 200, 2)))),
+                NamedEstimator::new("api", estimator(Err(Error::NoData))),
+                NamedEstimator::new("cache", estimator(Err(Error::StaleCache)))
+                    .with_label("kind", "background"),
+            ],
+            params(Policy::Median, 2),
+        );
+        let result = aggregating.estimate().now_or_never().unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::NoQuorum {
+                available: 1,
+                required: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn legacy_estimates_give_legacy_result() {
+        let aggregating = AggregatingGasPriceEstimating::new(
+            vec![
+                estimator(Ok(eip1559(100, 200, 2))),
+                estimator(Ok(GasPrice::Legacy {
+                    gas_price: 110.into(),
+                })),
+            ],
+            params(Policy::Min, 2),
+        );
+        let gas_price = aggregating.estimate().now_or_never().unwrap().unwrap();
+        assert_eq!(
+            gas_price,
+            GasPrice::Legacy {
+                gas_price: 102.into()
+            }
+        );
+    }
+
+    #[test]
+    fn detailed_combines_metadata() {
+        let detailed = |source: &'static str, block_number: u64, age: u64| {
+            let mut estimator = MockGasPriceEstimating::new();
+            estimator.expect_estimate_detailed().returning(move |_| {
+                Ok(
+                    Estimate::new(eip1559(100, 200, 2).with_blob_fee(5.into()), source)
+                        .with_block_number(block_number)
+                        .with_age(Duration::from_secs(age)),
+                )
+            });
+            Box::new(estimator) as Box<dyn GasPriceEstimating>
+        };
+        let aggregating = AggregatingGasPriceEstimating::new(
+            vec![detailed("a", 10, 1), detailed("b", 11, 3)],
+            params(Policy::Median, 2),
+        );
+        let estimate = aggregating
+            .estimate_detailed(Default::default())
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(estimate.gas_price.max_fee_per_blob_gas(), Some(5.into()));
+        assert_eq!(estimate.source, "aggregate(a, b)");
+        assert_eq!(estimate.block_number, Some(11));
+        assert_eq!(estimate.age, Some(Duration::from_secs(3)));
+    }
+}
@@ -0,0 +1,531 @@
+use super::{Estimate, EstimationRequest, GasPrice, GasPriceEstimating};
+use crate::{Error, Result};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::task::{self, JoinHandle};
+
+/// Produces the value kept up to date by `BackgroundRefresh`.
+#[async_trait::async_trait]
+pub trait Refresh: Send + Sync + 'static {
+    type Value: Clone + Send + Sync + 'static;
+
+    async fn refresh(&self) -> Result<Self::Value>;
+}
+
+// Allows `BackgroundRefresh<Box<dyn Refresh<Value = T>>>` to hide the type of the refresh.
+#[async_trait::async_trait]
+impl<R: Refresh + ?Sized> Refresh for Box<R> {
+    type Value = R::Value;
+
+    async fn refresh(&self) -> Result<Self::Value> {
+        self.as_ref().refresh().await
+    }
+}
+
+/// Decides when `BackgroundRefresh` refreshes again after a successful refresh.
+///
+/// Failed refreshes are retried after the retry interval of the `Config` regardless of the trigger.
+/// If the trigger fails the refresh happens after the retry interval instead, so a broken trigger
+/// degrades to refreshing periodically.
+#[async_trait::async_trait]
+pub trait Trigger: Send + 'static {
+    /// Returns once the next refresh is due.
+    async fn next(&mut self) -> Result<()>;
+}
+
+/// Refreshes after a fixed time, the trigger used by `BackgroundRefresh::new`.
+pub struct Interval(pub Duration);
+
+#[async_trait::async_trait]
+impl Trigger for Interval {
+    async fn next(&mut self) -> Result<()> {
+        tokio::time::sleep(self.0).await;
+        Ok(())
+    }
+}
+
+/// How `BackgroundRefresh::new` gets the first value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InitialFetch {
+    /// Wait for the first value and fail if it cannot be fetched.
+    #[default]
+    Required,
+    /// Wait for the first value but start without one if it cannot be fetched.
+    BestEffort,
+    /// Fetch the first value in the background task.
+    Background,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // time between the end of a successful refresh and the start of the next one, unless a
+    // different `Trigger` is used
+    pub interval: Duration,
+    // values older than this are not handed out
+    pub validity: Duration,
+    pub initial_fetch: InitialFetch,
+    // time between the end of a failed refresh and the next attempt, the interval if not set
+    pub retry_interval: Option<Duration>,
+    // whether a failed refresh discards the cached value instead of keeping it until it is stale
+    pub clear_on_error: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            validity: Duration::from_secs(60),
+            initial_fetch: InitialFetch::Required,
+            retry_interval: None,
+            clear_on_error: false,
+        }
+    }
+}
+
+// Presets for chains, the default is suited to rate limited APIs. Faster chains need more frequent
+// refreshes for the cached values to keep up with the blocks.
+impl Config {
+    /// Refreshes twice per block. Values stay valid for five blocks but at least 10 s.
+    pub fn for_block_time(block_time: Duration) -> Self {
+        Self {
+            interval: block_time / 2,
+            validity: (block_time * 5).max(Duration::from_secs(10)),
+            ..Default::default()
+        }
+    }
+
+    /// Ethereum mainnet, 12 s blocks.
+    pub fn mainnet() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            validity: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+
+    /// Gnosis Chain, 5 s blocks.
+    pub fn gnosis() -> Self {
+        Self::for_block_time(Duration::from_secs(5))
+    }
+
+    /// Polygon PoS, 2 s blocks.
+    pub fn polygon() -> Self {
+        Self::for_block_time(Duration::from_secs(2))
+    }
+
+    /// OP Mainnet and Base, 2 s blocks.
+    pub fn optimism() -> Self {
+        Self::for_block_time(Duration::from_secs(2))
+    }
+
+    /// Arbitrum One. Blocks are produced every 250 ms which is faster than nodes should be polled
+    /// so this refreshes every second.
+    pub fn arbitrum() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            validity: Duration::from_secs(10),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    pub fn with_validity(self, validity: Duration) -> Self {
+        Self { validity, ..self }
+    }
+}
+
+// The cached value and the time it was fetched at.
+type Cache<T> = Arc<Mutex<Option<(Instant, T)>>>;
+
+/// Caches the value of a `Refresh` and refreshes it periodically in a background task, so that
+/// reading it never waits for the network.
+///
+/// The task is aborted when the `BackgroundRefresh` is dropped.
+///
+/// Estimators can be wrapped through `EstimateRefresh`.
+pub struct BackgroundRefresh<E: Refresh> {
+    name: String,
+    refresh: Arc<E>,
+    validity: Duration,
+    cache: Cache<E::Value>,
+    handle: JoinHandle<()>,
+}
+
+impl<E: Refresh> Drop for BackgroundRefresh<E> {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl<E: Refresh> BackgroundRefresh<E> {
+    /// The name is used in logs and metrics.
+    pub async fn new(name: impl Into<String>, refresh: E, config: Config) -> Result<Self> {
+        let interval = Interval(config.interval);
+        Self::with_trigger(name, refresh, config, interval).await
+    }
+
+    /// Refreshes whenever `trigger` fires instead of after the interval of the config.
+    pub async fn with_trigger(
+        name: impl Into<String>,
+        refresh: E,
+        config: Config,
+        mut trigger: impl Trigger,
+    ) -> Result<Self> {
+        let name = name.into();
+        let refresh = Arc::new(refresh);
+        let cache: Cache<E::Value> = Default::default();
+
+        if config.initial_fetch != InitialFetch::Background {
+            match refresh.refresh().await {
+                Ok(value) => *cache.lock().unwrap() = Some((Instant::now(), value)),
+                Err(err) => {
+                    tracing::warn!(estimator = %name, ?err, "failed initial refresh");
+                    if config.initial_fetch == InitialFetch::Required {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+
+        let retry_interval = config.retry_interval.unwrap_or(config.interval);
+        // Delay before the next refresh if it doesn't wait for the trigger.
+        let mut retry = match config.initial_fetch {
+            InitialFetch::Background => Some(Duration::ZERO),
+            _ if cache.lock().unwrap().is_some() => None,
+            _ => Some(retry_interval),
+        };
+        let handle = task::spawn({
+            let (name, refresh, cache) = (name.clone(), refresh.clone(), cache.clone());
+            async move {
+                loop {
+                    match retry {
+                        Some(delay) => tokio::time::sleep(delay).await,
+                        None => {
+                            if let Err(err) = trigger.next().await {
+                                tracing::warn!(estimator = %name, ?err, "failed refresh trigger");
+                                tokio::time::sleep(retry_interval).await;
+                            }
+                        }
+                    }
+                    let start = Instant::now();
+                    let result = refresh.refresh().await;
+                    tracing::debug!(
+                        estimator = %name,
+                        "refreshed in {} s",
+                        start.elapsed().as_secs_f32()
+                    );
+                    crate::metrics::refresh(&name, start.elapsed());
+                    match result {
+                        Ok(value) => {
+                            *cache.lock().unwrap() = Some((Instant::now(), value));
+                            retry = None;
+                        }
+                        Err(err) => {
+                            tracing::warn!(estimator = %name, ?err, "failed refresh");
+                            if config.clear_on_error {
+                                *cache.lock().unwrap() = None;
+                            }
+                            retry = Some(retry_interval);
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            name,
+            refresh,
+            validity: config.validity,
+            cache,
+            handle,
+        })
+    }
+
+    /// The cached value together with its age.
+    ///
+    /// Fails with `Error::NoData` if there is no value and with `Error::StaleCache` if it is older
+    /// than the validity.
+    pub fn get(&self) -> Result<(E::Value, Duration)> {
+        let (time, value) = self.cache.lock().unwrap().clone().ok_or(Error::NoData)?;
+        let age = Instant::now().saturating_duration_since(time);
+        if age > self.validity {
+            crate::metrics::stale_cache_hit(&self.name);
+            return Err(Error::StaleCache);
+        }
+        Ok((value, age))
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.refresh
+    }
+}
+
+/// Refreshes the estimate of an estimator for a fixed request.
+///
+/// `BackgroundRefresh<EstimateRefresh<E>>` is an estimator which answers that request from the
+/// cache and forwards all other requests to the wrapped estimator.
+pub struct EstimateRefresh<E> {
+    estimator: E,
+    request: EstimationRequest,
+}
+
+impl<E: GasPriceEstimating> EstimateRefresh<E> {
+    pub fn new(estimator: E, request: EstimationRequest) -> Self {
+        Self { estimator, request }
+    }
+}
+
+#[async_trait::async_trait]
+impl<E: GasPriceEstimating + 'static> Refresh for EstimateRefresh<E> {
+    type Value = Estimate;
+
+    async fn refresh(&self) -> Result<Estimate> {
+        self.estimator.estimate_detailed(self.request).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<E: GasPriceEstimating + 'static> GasPriceEstimating for BackgroundRefresh<EstimateRefresh<E>> {
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
+        self.estimate_with_request(
+            EstimationRequest::default()
+                .with_gas_limit(gas_limit)
+                .with_time_limit(time_limit),
+        )
+        .await
+    }
+
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        Ok(self.estimate_detailed(request).await?.gas_price)
+    }
+
+    // The age of a cached estimate includes the time it spent in the cache.
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        let inner = self.inner();
+        if request != inner.request {
+            return inner.estimator.estimate_detailed(request).await;
+        }
+        let (estimate, age) = self.get()?;
+        let age = estimate.age.unwrap_or_default() + age;
+        Ok(estimate.with_age(age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{GasPrice1559, MockGasPriceEstimating};
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Returns the queued results in order and fails with `Error::NoData` once they are used up.
+    struct Queue(Mutex<VecDeque<Result<u64>>>);
+
+    impl Queue {
+        fn new(results: Vec<Result<u64>>) -> Self {
+            Self(Mutex::new(results.into()))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Refresh for Queue {
+        type Value = u64;
+
+        async fn refresh(&self) -> Result<u64> {
+            self.0
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or(Err(Error::NoData))
+        }
+    }
+
+    fn config(initial_fetch: InitialFetch) -> Config {
+        Config {
+            interval: Duration::from_millis(10),
+            validity: Duration::from_secs(60),
+            initial_fetch,
+            retry_interval: None,
+            clear_on_error: false,
+        }
+    }
+
+    fn value<E: Refresh>(refresh: &BackgroundRefresh<E>) -> Result<E::Value> {
+        Ok(refresh.get()?.0)
+    }
+
+    #[test]
+    fn presets_follow_block_time() {
+        let config = Config::for_block_time(Duration::from_secs(2));
+        assert_eq!(config.interval, Duration::from_secs(1));
+        assert_eq!(config.validity, Duration::from_secs(10));
+        let config = Config::for_block_time(Duration::from_secs(12));
+        assert_eq!(config.interval, Duration::from_secs(6));
+        assert_eq!(config.validity, Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn initial_fetch_policies() {
+        let failing = || Queue::new(vec![Err(Error::Timeout), Ok(1)]);
+
+        let result =
+            BackgroundRefresh::new("background", failing(), config(InitialFetch::Required)).await;
+        assert!(matches!(result, Err(Error::Timeout)));
+
+        let refresh =
+            BackgroundRefresh::new("background", failing(), config(InitialFetch::BestEffort))
+                .await
+                .unwrap();
+        assert!(matches!(value(&refresh), Err(Error::NoData)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(value(&refresh).unwrap(), 1);
+
+        let refresh = BackgroundRefresh::new(
+            "background",
+            Queue::new(vec![Ok(1)]),
+            config(InitialFetch::Background),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(value(&refresh), Err(Error::NoData)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(value(&refresh).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_value_until_stale() {
+        let refresh = BackgroundRefresh::new(
+            "background",
+            Queue::new(vec![Ok(1), Ok(2)]),
+            config(InitialFetch::Required),
+        )
+        .await
+        .unwrap();
+        assert_eq!(value(&refresh).unwrap(), 1);
+        // the refreshes after the second value fail
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(value(&refresh).unwrap(), 2);
+
+        let refresh = BackgroundRefresh::new(
+            "background",
+            Queue::new(vec![Ok(1)]),
+            Config {
+                validity: Duration::ZERO,
+                ..config(InitialFetch::Required)
+            },
+        )
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(matches!(value(&refresh), Err(Error::StaleCache)));
+    }
+
+    // Fires once for every message.
+    struct Channel(tokio::sync::mpsc::UnboundedReceiver<()>);
+
+    #[async_trait::async_trait]
+    impl Trigger for Channel {
+        async fn next(&mut self) -> Result<()> {
+            self.0.recv().await.ok_or(Error::NoData)
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_on_trigger() {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let refresh = BackgroundRefresh::with_trigger(
+            "background",
+            Queue::new(vec![Ok(1), Ok(2), Ok(3)]),
+            config(InitialFetch::Required),
+            Channel(receiver),
+        )
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(value(&refresh).unwrap(), 1);
+        sender.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(value(&refresh).unwrap(), 2);
+    }
+
+    // Fails on every call.
+    struct Broken;
+
+    #[async_trait::async_trait]
+    impl Trigger for Broken {
+        async fn next(&mut self) -> Result<()> {
+            Err(Error::transport("broken"))
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_after_trigger_error() {
+        let refresh = BackgroundRefresh::with_trigger(
+            "background",
+            Queue::new(vec![Ok(1), Ok(2)]),
+            config(InitialFetch::Required),
+            Broken,
+        )
+        .await
+        .unwrap();
+        assert_eq!(value(&refresh).unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(value(&refresh).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn clears_value_on_error() {
+        let refresh = BackgroundRefresh::new(
+            "background",
+            Queue::new(vec![Ok(1)]),
+            Config {
+                clear_on_error: true,
+                ..config(InitialFetch::Required)
+            },
+        )
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(value(&refresh), Err(Error::NoData)));
+    }
+
+    #[tokio::test]
+    async fn caches_estimates_of_estimator() {
+        let gas_price = GasPrice::from(GasPrice1559 {
+            base_fee_per_gas: 1.into(),
+            max_fee_per_gas: 3.into(),
+            max_priority_fee_per_gas: 2.into(),
+        });
+        let mut estimator = MockGasPriceEstimating::new();
+        estimator
+            .expect_estimate_detailed()
+            .times(2)
+            .returning(move |_| Ok(Estimate::new(gas_price, "mock")));
+        let refresh = BackgroundRefresh::new(
+            "background",
+            EstimateRefresh::new(estimator, Default::default()),
+            Config {
+                interval: Duration::from_secs(60),
+                ..config(InitialFetch::Required)
+            },
+        )
+        .await
+        .unwrap();
+
+        // answered from the cache
+        let estimate = refresh.estimate_detailed(Default::default()).await.unwrap();
+        assert_eq!(estimate.gas_price, gas_price);
+        assert!(estimate.age.is_some());
+        assert_eq!(refresh.estimate().await.unwrap(), gas_price);
+        // forwarded to the estimator
+        let estimate = refresh
+            .estimate_detailed(EstimationRequest::default().with_gas_limit(1e6))
+            .await
+            .unwrap();
+        assert_eq!(estimate.age, None);
+    }
+}
@@ -0,0 +1,162 @@
+//! EIP-1559 base fee computation.
+
+use crate::U256;
+use primitive_types::U512;
+use std::convert::TryFrom;
+
+/// Parameters of the EIP-1559 base fee update rule of a chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params {
+    // ratio of the gas limit to the gas target
+    pub elasticity: u64,
+    // inverse of the largest relative change of the base fee from one block to the next at the
+    // gas target
+    pub denominator: u64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self::MAINNET
+    }
+}
+
+impl Params {
+    /// Ethereum mainnet and other chains following EIP-1559 as specified.
+    pub const MAINNET: Self = Self {
+        elasticity: 2,
+        denominator: 8,
+    };
+    /// OP Mainnet and Base since the Canyon upgrade.
+    pub const OPTIMISM: Self = Self {
+        elasticity: 6,
+        denominator: 250,
+    };
+    /// Polygon PoS since the Delhi upgrade.
+    pub const POLYGON: Self = Self {
+        elasticity: 2,
+        denominator: 16,
+    };
+
+    /// Base fee of the block after a block with the given base fee, gas used and gas limit.
+    pub fn next_base_fee(&self, parent_base_fee: U256, gas_used: U256, gas_limit: U256) -> U256 {
+        next_base_fee(
+            parent_base_fee,
+            gas_used,
+            gas_limit / self.elasticity.max(1),
+            self.elasticity,
+            self.denominator,
+        )
+    }
+
+    /// Highest base fee `n_blocks` blocks after a block with base fee `base_fee`, reached if that
+    /// block and all blocks in between are full.
+    pub fn max_base_fee_after(&self, base_fee: U256, n_blocks: u64) -> U256 {
+        // Only the ratio of gas used to gas target matters.
+        let full = U256::from(self.elasticity);
+        self.after(base_fee, n_blocks, full)
+    }
+
+    /// Lowest base fee `n_blocks` blocks after a block with base fee `base_fee`, reached if that
+    /// block and all blocks in between are empty.
+    pub fn min_base_fee_after(&self, base_fee: U256, n_blocks: u64) -> U256 {
+        self.after(base_fee, n_blocks, U256::zero())
+    }
+
+    // Base fee after `n_blocks` blocks using `gas_used` of a gas target of 1.
+    fn after(&self, base_fee: U256, n_blocks: u64, gas_used: U256) -> U256 {
+        let mut base_fee = base_fee;
+        for _ in 0..n_blocks {
+            let next = next_base_fee(
+                base_fee,
+                gas_used,
+                U256::one(),
+                self.elasticity,
+                self.denominator,
+            );
+            if next == base_fee {
+                break;
+            }
+            base_fee = next;
+        }
+        base_fee
+    }
+}
+
+/// Base fee of the block after a block with base fee `parent_base_fee` that used `gas_used` gas
+/// according to EIP-1559.
+///
+/// The base fee changes by the relative deviation of the gas used from `gas_target` divided by
+/// `denominator`, and rises by at least 1 wei if the gas used is above the target. The gas used is
+/// capped at the gas limit `gas_target * elasticity`.
+pub fn next_base_fee(
+    parent_base_fee: U256,
+    gas_used: U256,
+    gas_target: U256,
+    elasticity: u64,
+    denominator: u64,
+) -> U256 {
+    if gas_target.is_zero() || denominator == 0 {
+        return parent_base_fee;
+    }
+    let gas_used = gas_used.min(gas_target.saturating_mul(elasticity.into()));
+    let delta = |gas_delta: U256| {
+        let delta = parent_base_fee.full_mul(gas_delta) / U512::from(gas_target) / denominator;
+        U256::try_from(delta).unwrap_or(U256::MAX)
+    };
+    if gas_used > gas_target {
+        parent_base_fee.saturating_add(delta(gas_used - gas_target).max(U256::one()))
+    } else {
+        parent_base_fee - delta(gas_target - gas_used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_base_fee_mainnet() {
+        let next = |gas_used: u64| {
+            Params::MAINNET.next_base_fee(800.into(), gas_used.into(), 30_000_000.into())
+        };
+        assert_eq!(next(15_000_000), 800.into());
+        assert_eq!(next(30_000_000), 900.into());
+        assert_eq!(next(0), 700.into());
+        assert_eq!(next(22_500_000), 850.into());
+        // gas used above the gas limit counts as a full block
+        assert_eq!(next(60_000_000), 900.into());
+        // rises by at least 1 wei
+        assert_eq!(
+            next_base_fee(7.into(), 15_000_001.into(), 15_000_000.into(), 2, 8),
+            8.into()
+        );
+        assert_eq!(
+            next_base_fee(U256::MAX, 2.into(), 1.into(), 2, 8),
+            U256::MAX
+        );
+    }
+
+    #[test]
+    fn next_base_fee_optimism() {
+        let next = |gas_used: u64| {
+            Params::OPTIMISM.next_base_fee(1_000_000.into(), gas_used.into(), 30_000_000.into())
+        };
+        assert_eq!(next(5_000_000), 1_000_000.into());
+        // 2% per full block
+        assert_eq!(next(30_000_000), 1_020_000.into());
+        // 0.4% per empty block
+        assert_eq!(next(0), 996_000.into());
+    }
+
+    #[test]
+    fn base_fee_bounds_after_blocks() {
+        let params = Params::MAINNET;
+        assert_eq!(params.max_base_fee_after(800.into(), 0), 800.into());
+        assert_eq!(params.max_base_fee_after(800.into(), 1), 900.into());
+        assert_eq!(params.max_base_fee_after(800.into(), 2), 1012.into());
+        assert_eq!(params.min_base_fee_after(800.into(), 2), 613.into());
+        assert_eq!(params.max_base_fee_after(0.into(), 3), 3.into());
+        assert_eq!(params.min_base_fee_after(1.into(), 1000), 1.into());
+        assert_eq!(params.max_base_fee_after(U256::MAX / 2, 10_000), U256::MAX);
+    }
+}
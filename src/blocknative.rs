@@ -1,20 +1,19 @@
-use super::{linear_interpolation, GasPrice1559, GasPriceEstimating, Transport};
-use anyhow::{anyhow, Context, Result};
-use serde::Deserialize;
-use std::{
-    convert::TryInto,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+use super::{
+    background::{self, BackgroundRefresh, Refresh},
+    gas_price::wei_from_f64,
+    linear_interpolation, Estimate, EstimationRequest, GasPrice, GasPrice1559, GasPriceEstimating,
+    Transport,
 };
-use tokio::task::{self, JoinHandle};
+use crate::{Error, Result};
+use serde::Deserialize;
+use std::{convert::TryInto, time::Duration};
 
 // Gas price estimation with https://www.blocknative.com/gas-estimator , api https://docs.blocknative.com/gas-platform#example-request .
 
 const API_URI: &str = "https://api.blocknative.com/gasprices/blockprices";
+const NAME: &str = "blocknative";
 
 const TIME_PER_BLOCK: Duration = Duration::from_secs(15);
-const RATE_LIMIT: Duration = Duration::from_secs(10);
-const CACHED_RESPONSE_VALIDITY: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Deserialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
@@ -59,6 +58,7 @@ impl BlockPrice {
 #[derive(Debug, serde::Deserialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 struct Response {
+    current_block_number: Option<u64>,
     block_prices: Vec<BlockPrice>,
 }
 
@@ -70,25 +70,7 @@ impl Response {
                 .into_iter()
                 .map(|block| block.gwei_to_wei())
                 .collect(),
-        }
-    }
-}
-
-/// Used for rate limit implementation. If requests are received at a higher rate then Gas price estimators
-/// can handle, we need to have a cached value that will be returned instead of error.
-#[derive(Debug, Clone)]
-pub struct CachedResponse {
-    // The time at which the request was sent.
-    time: Instant,
-    // The result of the last response. Error isn't Clone so we store None in the error case.
-    data: Response,
-}
-
-impl Default for CachedResponse {
-    fn default() -> Self {
-        Self {
-            time: Instant::now(),
-            data: Default::default(),
+            ..self
         }
     }
 }
@@ -98,95 +80,109 @@ struct Request<T> {
     header: http::header::HeaderMap,
 }
 
-impl<T: Transport> Request<T> {
-    async fn gas_price(&self) -> Result<Response> {
-        self.transport
+#[async_trait::async_trait]
+impl<T: Transport + 'static> Refresh for Request<T> {
+    type Value = Response;
+
+    async fn refresh(&self) -> Result<Response> {
+        let response: Response = self
+            .transport
             .get_json(API_URI, self.header.clone())
-            .await
-            .context("failed to get blocknative gas price")
+            .await?;
+        Ok(response.gwei_to_wei())
     }
 }
 
+/// Produces `GasPrice::Eip1559` estimates.
+///
+/// The API response is cached and refreshed in the background.
 pub struct BlockNative {
-    cached_response: Arc<Mutex<CachedResponse>>,
-    handle: JoinHandle<()>,
-}
-
-impl Drop for BlockNative {
-    fn drop(&mut self) {
-        self.handle.abort();
-        self.cached_response = Default::default();
-    }
+    cache: BackgroundRefresh<Box<dyn Refresh<Value = Response>>>,
 }
 
 impl BlockNative {
+    /// Refreshes every 10 s, responses stay valid for 60 s. Fails if the initial request fails.
     pub async fn new<T: Transport + 'static>(
         transport: T,
         header: http::header::HeaderMap,
     ) -> Result<Self> {
-        let cached_response: Arc<Mutex<CachedResponse>> = Default::default();
-        let cached_response_clone = cached_response.clone();
-
-        //send one request to initially populate the cached response
-        let request = Request { transport, header };
-        match request.gas_price().await {
-            Ok(response) => {
-                *cached_response_clone.lock().unwrap() = CachedResponse {
-                    time: Instant::now(),
-                    data: response.gwei_to_wei(),
-                };
-            }
-            Err(err) => {
-                tracing::warn!(?err, "failed to get initial response from blocknative");
-                return Err(anyhow!("failed to get initial response from blocknative"));
-            }
-        }
-
-        //spawn task for updating the cached response every RATE_LIMIT seconds
-        let handle = task::spawn(async move {
-            loop {
-                tokio::time::sleep(RATE_LIMIT).await;
-                match request.gas_price().await {
-                    Ok(response) => {
-                        *cached_response_clone.lock().unwrap() = CachedResponse {
-                            time: Instant::now(),
-                            data: response.gwei_to_wei(),
-                        };
-                    }
-                    Err(err) => tracing::warn!(?err, "failed to get response from blocknative"),
-                }
-            }
-        });
+        Self::with_config(transport, header, Default::default()).await
+    }
 
-        Ok(Self {
-            cached_response,
-            handle,
-        })
+    /// Refreshes according to `config`, for example to stay within the quota of the API plan.
+    pub async fn with_config<T: Transport + 'static>(
+        transport: T,
+        header: http::header::HeaderMap,
+        config: background::Config,
+    ) -> Result<Self> {
+        let request: Box<dyn Refresh<Value = Response>> = Box::new(Request { transport, header });
+        let cache = BackgroundRefresh::new(NAME, request, config).await?;
+        Ok(Self { cache })
     }
 }
 
 #[async_trait::async_trait]
 impl GasPriceEstimating for BlockNative {
-    async fn estimate_with_limits(
-        &self,
-        _gas_limit: f64,
-        time_limit: Duration,
-    ) -> Result<GasPrice1559> {
-        let cached_response = self.cached_response.lock().unwrap().clone();
-
-        estimate_with_limits(time_limit, cached_response)
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
+        self.estimate_with_request(
+            EstimationRequest::default()
+                .with_gas_limit(gas_limit)
+                .with_time_limit(time_limit),
+        )
+        .await
+    }
+
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        Ok(self.estimate_detailed(request).await?.gas_price)
+    }
+
+    // Honours `confidence` in addition to the fields handled by `EstimationRequest::apply`.
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        let time_limit = time_limit(&request)?;
+        let (response, age) = self.cache.get()?;
+
+        let block_number = response.current_block_number;
+        let confidence = confidence(time_limit, &response);
+        let gas_price = request.apply(estimate_with_limits(time_limit, response)?.into())?;
+        Ok(Estimate {
+            block_number,
+            confidence,
+            ..Estimate::new(gas_price, NAME).with_age(age)
+        })
     }
 }
 
-fn estimate_with_limits(
-    time_limit: Duration,
-    mut cached_response: CachedResponse,
-) -> Result<GasPrice1559> {
-    if Instant::now().saturating_duration_since(cached_response.time) > CACHED_RESPONSE_VALIDITY {
-        return Err(anyhow!("cached response is stale"));
+// Confidence of the estimate for a time limit, the inverse of the mapping in `time_limit`
+// clamped to the confidences BlockNative returned.
+fn confidence(time_limit: Duration, response: &Response) -> Option<f64> {
+    let confidences = response
+        .block_prices
+        .first()?
+        .estimated_prices
+        .iter()
+        .map(|estimated_price| estimated_price.confidence / 100.0);
+    let min = confidences.clone().reduce(f64::min)?;
+    let max = confidences.reduce(f64::max)?;
+    Some((TIME_PER_BLOCK.as_secs_f64() / time_limit.as_secs_f64()).clamp(min, max))
+}
+
+// Estimates are keyed by the time limit derived from their confidence, so a requested confidence is
+// mapped to the same time limit.
+fn time_limit(request: &EstimationRequest) -> Result<Duration> {
+    match request.confidence {
+        Some(confidence) if confidence > 0.0 && confidence <= 1.0 => {
+            Ok(TIME_PER_BLOCK.div_f64(confidence))
+        }
+        Some(confidence) => Err(Error::Validation(format!(
+            "invalid confidence {}",
+            confidence
+        ))),
+        None => Ok(request.time_limit),
     }
+}
 
-    if let Some(block) = cached_response.data.block_prices.first_mut() {
+fn estimate_with_limits(time_limit: Duration, mut response: Response) -> Result<GasPrice1559> {
+    if let Some(block) = response.block_prices.first_mut() {
         //need to sort by confidence since Blocknative API does not guarantee sorted response
         block
             .estimated_prices
@@ -224,20 +220,20 @@ fn estimate_with_limits(
             .collect::<Vec<(f64, f64)>>();
 
         return GasPrice1559 {
-            max_fee_per_gas: linear_interpolation::interpolate(
+            max_fee_per_gas: wei_from_f64(linear_interpolation::interpolate(
                 time_limit.as_secs_f64(),
                 max_fee_per_gas_points.as_slice().try_into()?,
-            ),
-            max_priority_fee_per_gas: linear_interpolation::interpolate(
+            ))?,
+            max_priority_fee_per_gas: wei_from_f64(linear_interpolation::interpolate(
                 time_limit.as_secs_f64(),
                 max_priority_fee_per_gas_points.as_slice().try_into()?,
-            ),
-            base_fee_per_gas: block.base_fee_per_gas,
+            ))?,
+            base_fee_per_gas: wei_from_f64(block.base_fee_per_gas)?,
         }
         .validate();
     }
 
-    Err(anyhow!("no valid response exist"))
+    Err(Error::NoData)
 }
 
 #[cfg(test)]
@@ -294,6 +290,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn time_limit_from_confidence() {
+        let request = EstimationRequest::default().with_time_limit(Duration::from_secs(20));
+        assert_eq!(time_limit(&request).unwrap(), Duration::from_secs(20));
+        assert_eq!(
+            time_limit(&request.with_confidence(0.75)).unwrap(),
+            Duration::from_secs(20)
+        );
+        assert!(time_limit(&request.with_confidence(0.0)).is_err());
+        assert!(time_limit(&request.with_confidence(1.5)).is_err());
+    }
+
+    #[test]
+    fn confidence_from_time_limit() {
+        let response: Response = serde_json::from_value(json!({
+            "currentBlockNumber": 13005095,
+            "blockPrices": [{
+                "baseFeePerGas": 94.6,
+                "estimatedPrices": [
+                    { "confidence": 99, "price": 104, "maxPriorityFeePerGas": 9.86, "maxFeePerGas": 199.16 },
+                    { "confidence": 70, "price": 96, "maxPriorityFeePerGas": 1.74, "maxFeePerGas": 191.04 }
+                ]
+            }]
+        }))
+        .unwrap();
+        assert_eq!(response.current_block_number, Some(13005095));
+        assert_eq!(confidence(Duration::from_secs(0), &response), Some(0.99));
+        assert_eq!(confidence(Duration::from_secs(20), &response), Some(0.75));
+        assert_eq!(confidence(Duration::from_secs(60), &response), Some(0.7));
+        assert_eq!(
+            confidence(Duration::from_secs(20), &Response::default()),
+            None
+        );
+    }
+
     #[test]
     fn estimate_with_limits_test() {
         let json = json!({
@@ -343,55 +374,52 @@ mod tests {
             }
           ]
         });
-        let response: Response = serde_json::from_value(json).unwrap();
-        let cached_response = CachedResponse {
-            time: Instant::now(),
-            data: response,
-        };
-
-        let price = estimate_with_limits(Duration::from_secs(10), cached_response.clone()).unwrap();
+        let response = serde_json::from_value::<Response>(json)
+            .unwrap()
+            .gwei_to_wei();
+        let price = estimate_with_limits(Duration::from_secs(10), response.clone()).unwrap();
         assert_eq!(
             price,
             GasPrice1559 {
-                max_fee_per_gas: 199.16,
-                max_priority_fee_per_gas: 9.86,
-                base_fee_per_gas: 94.647990462,
+                max_fee_per_gas: 199_160_000_000u64.into(),
+                max_priority_fee_per_gas: 9_860_000_000u64.into(),
+                base_fee_per_gas: 94_647_990_462u64.into(),
             }
         );
-        let price = estimate_with_limits(Duration::from_secs(16), cached_response.clone()).unwrap();
+        let price = estimate_with_limits(Duration::from_secs(16), response.clone()).unwrap();
         assert_eq!(
             price,
             GasPrice1559 {
-                max_fee_per_gas: 194.134,
-                max_priority_fee_per_gas: 4.844000000000001,
-                base_fee_per_gas: 94.647990462,
+                max_fee_per_gas: 194_134_000_000u64.into(),
+                max_priority_fee_per_gas: 4_844_000_000u64.into(),
+                base_fee_per_gas: 94_647_990_462u64.into(),
             }
         );
-        let price = estimate_with_limits(Duration::from_secs(17), cached_response.clone()).unwrap();
+        let price = estimate_with_limits(Duration::from_secs(17), response.clone()).unwrap();
         assert_eq!(
             price,
             GasPrice1559 {
-                max_fee_per_gas: 193.2612,
-                max_priority_fee_per_gas: 3.9696000000000007,
-                base_fee_per_gas: 94.647990462,
+                max_fee_per_gas: 193_261_200_000u64.into(),
+                max_priority_fee_per_gas: 3_969_600_000u64.into(),
+                base_fee_per_gas: 94_647_990_462u64.into(),
             }
         );
-        let price = estimate_with_limits(Duration::from_secs(19), cached_response.clone()).unwrap();
+        let price = estimate_with_limits(Duration::from_secs(19), response.clone()).unwrap();
         assert_eq!(
             price,
             GasPrice1559 {
-                max_fee_per_gas: 192.1552,
-                max_priority_fee_per_gas: 2.8552000000000004,
-                base_fee_per_gas: 94.647990462,
+                max_fee_per_gas: 192_155_200_000u64.into(),
+                max_priority_fee_per_gas: 2_855_200_000u64.into(),
+                base_fee_per_gas: 94_647_990_462u64.into(),
             }
         );
-        let price = estimate_with_limits(Duration::from_secs(25), cached_response).unwrap();
+        let price = estimate_with_limits(Duration::from_secs(25), response).unwrap();
         assert_eq!(
             price,
             GasPrice1559 {
-                max_fee_per_gas: 191.04,
-                max_priority_fee_per_gas: 1.74,
-                base_fee_per_gas: 94.647990462,
+                max_fee_per_gas: 191_040_000_000u64.into(),
+                max_priority_fee_per_gas: 1_740_000_000u64.into(),
+                base_fee_per_gas: 94_647_990_462u64.into(),
             }
         );
     }
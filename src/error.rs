@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Error returned by gas price estimators and transports.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The cached response is older than its validity period.
+    #[error("cached response is stale")]
+    StaleCache,
+    /// No usable response has been cached yet.
+    #[error("no cached data exist")]
+    NoData,
+    /// Data could not be fetched.
+    #[error("transport failure: {0}")]
+    Transport(#[source] BoxError),
+    /// The server answered with a non success status code.
+    #[error("unexpected http status {status}")]
+    Status {
+        status: http::StatusCode,
+        // Delay requested by the server through the `Retry-After` header.
+        retry_after: Option<Duration>,
+    },
+    /// The operation did not finish in time.
+    #[error("timed out")]
+    Timeout,
+    /// Fetched data could not be parsed or is missing fields.
+    #[error("parse failure: {0}")]
+    Parse(#[source] BoxError),
+    /// Values do not satisfy the expected invariants.
+    #[error("{0}")]
+    Validation(String),
+    /// The estimator was skipped because it failed too often recently.
+    #[error("circuit breaker is open")]
+    CircuitOpen,
+    /// Fewer estimates than required remained after discarding failures and outliers.
+    #[error("only {available} of the required {required} estimates are available")]
+    NoQuorum { available: usize, required: usize },
+    /// Every estimator failed, contains the individual errors in order.
+    #[error("all gas estimators failed: [{}]", join(.0))]
+    AllFailed(Vec<Error>),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn transport(err: impl Into<BoxError>) -> Self {
+        Self::Transport(err.into())
+    }
+
+    pub fn parse(err: impl Into<BoxError>) -> Self {
+        Self::Parse(err.into())
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        Self::Validation(message.into())
+    }
+
+    // The same error for caches that return it more than once. The sources of transport and parse
+    // errors can't be cloned so they are replaced by their messages.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Self::StaleCache => Self::StaleCache,
+            Self::NoData => Self::NoData,
+            Self::Transport(err) => Self::transport(err.to_string()),
+            Self::Status {
+                status,
+                retry_after,
+            } => Self::Status {
+                status: *status,
+                retry_after: *retry_after,
+            },
+            Self::Timeout => Self::Timeout,
+            Self::Parse(err) => Self::parse(err.to_string()),
+            Self::Validation(message) => Self::Validation(message.clone()),
+            Self::CircuitOpen => Self::CircuitOpen,
+            Self::NoQuorum {
+                available,
+                required,
+            } => Self::NoQuorum {
+                available: *available,
+                required: *required,
+            },
+            Self::AllFailed(errors) => {
+                Self::AllFailed(errors.iter().map(Self::duplicate).collect())
+            }
+        }
+    }
+}
+
+fn join(errors: &[Error]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Parse(err.into())
+    }
+}
+
+#[cfg(feature = "web3_")]
+impl From<web3::Error> for Error {
+    fn from(err: web3::Error) -> Self {
+        match err {
+            web3::Error::Decoder(_) | web3::Error::InvalidResponse(_) => Self::Parse(err.into()),
+            _ => Self::Transport(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_failed_lists_errors() {
+        let err = Error::AllFailed(vec![Error::StaleCache, Error::transport("timeout")]);
+        assert_eq!(
+            err.to_string(),
+            "all gas estimators failed: [cached response is stale; transport failure: timeout]"
+        );
+    }
+
+    #[test]
+    fn duplicate_keeps_variant_and_message() {
+        let err = Error::from(serde_json::from_str::<u64>("x").unwrap_err());
+        let duplicate = err.duplicate();
+        assert!(matches!(duplicate, Error::Parse(_)));
+        assert_eq!(duplicate.to_string(), err.to_string());
+        let err = Error::Status {
+            status: http::StatusCode::TOO_MANY_REQUESTS,
+            retry_after: Some(Duration::from_secs(1)),
+        };
+        assert!(matches!(
+            err.duplicate(),
+            Error::Status {
+                status: http::StatusCode::TOO_MANY_REQUESTS,
+                retry_after: Some(_),
+            }
+        ));
+    }
+}
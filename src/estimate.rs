@@ -0,0 +1,65 @@
+use crate::GasPrice;
+use std::time::Duration;
+
+/// Gas price estimate together with information about where it came from and how fresh it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub gas_price: GasPrice,
+    // Name of the estimator that produced the estimate.
+    pub source: String,
+    // Number of the latest block the estimate is based on, if known.
+    pub block_number: Option<u64>,
+    // Age of the data the estimate is based on, if known.
+    pub age: Option<Duration>,
+    // Probability in (0, 1] of the transaction being mined in the next block, if known.
+    pub confidence: Option<f64>,
+}
+
+impl Estimate {
+    pub fn new(gas_price: GasPrice, source: impl Into<String>) -> Self {
+        Self {
+            gas_price,
+            source: source.into(),
+            block_number: None,
+            age: None,
+            confidence: None,
+        }
+    }
+
+    pub fn with_block_number(self, block_number: u64) -> Self {
+        Self {
+            block_number: Some(block_number),
+            ..self
+        }
+    }
+
+    pub fn with_age(self, age: Duration) -> Self {
+        Self {
+            age: Some(age),
+            ..self
+        }
+    }
+
+    pub fn with_confidence(self, confidence: f64) -> Self {
+        Self {
+            confidence: Some(confidence),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} from {}", self.gas_price, self.source)?;
+        if let Some(block_number) = self.block_number {
+            write!(f, " at block {}", block_number)?;
+        }
+        if let Some(age) = self.age {
+            write!(f, " aged {:.1} s", age.as_secs_f64())?;
+        }
+        if let Some(confidence) = self.confidence {
+            write!(f, " with {:.0}% confidence", confidence * 100.0)?;
+        }
+        Ok(())
+    }
+}
@@ -1,35 +1,304 @@
 //! Ethereum node `GasPriceEstimating` implementation.
 
-use crate::GasPrice1559;
+use crate::{base_fee, Error, Estimate, EstimationRequest, GasPrice, GasPrice1559, U256};
 
 use super::GasPriceEstimating;
-use anyhow::{Context, Result};
-use primitive_types::U256;
+use crate::Result;
 use std::time::Duration;
-use web3::{Transport, Web3};
+use web3::{
+    helpers::CallFuture,
+    types::{Block, BlockNumber, H256},
+    Transport, Web3,
+};
+
+#[derive(Clone, Debug)]
+pub struct Params {
+    // base fee update rule of the chain
+    pub base_fee: base_fee::Params,
+    // time between blocks used to convert time limits into a number of blocks
+    pub block_time: Duration,
+    // largest number of full blocks after the next one whose base fee the max fee per gas covers,
+    // the default matches the longest deadline covered by `NativeGasEstimator`
+    pub max_fee_blocks: u64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            base_fee: Default::default(),
+            block_time: Duration::from_secs(12),
+            max_fee_blocks: 127,
+        }
+    }
+}
+
+/// Produces `GasPrice::Eip1559` estimates based on `eth_maxPriorityFeePerGas` and the base fee of
+/// the next block, computed from the latest block with the base fee rule of the chain. On chains
+/// without a base fee produces `GasPrice::Legacy` estimates based on `eth_gasPrice`.
+///
+/// The max fee per gas is the highest base fee reachable if all blocks until the deadline of the
+/// request are full plus the priority fee, so that the transaction stays valid until then. Like in
+/// `NativeGasEstimator` the time limit is converted into blocks with `Params::block_time`, and the
+/// covered blocks are capped at `Params::max_fee_blocks`.
+pub struct EthNode<T: Transport> {
+    web3: Web3<T>,
+    params: Params,
+}
+
+impl<T: Transport> EthNode<T> {
+    pub fn new(web3: Web3<T>, params: Params) -> Self {
+        Self { web3, params }
+    }
+}
 
+#[async_trait::async_trait]
+impl<T> GasPriceEstimating for EthNode<T>
+where
+    T: Transport + Send + Sync,
+    <T as Transport>::Out: Send,
+{
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
+        self.estimate_with_request(
+            EstimationRequest::default()
+                .with_gas_limit(gas_limit)
+                .with_time_limit(time_limit),
+        )
+        .await
+    }
+
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        Ok(detailed_estimate(&self.web3, &self.params, request)
+            .await?
+            .gas_price)
+    }
+
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        detailed_estimate(&self.web3, &self.params, request).await
+    }
+}
+
+/// Estimates like `EthNode` with the default `Params`, which follow the mainnet base fee rule.
 #[async_trait::async_trait]
 impl<T> GasPriceEstimating for Web3<T>
 where
     T: Transport + Send + Sync,
     <T as Transport>::Out: Send,
 {
-    async fn estimate_with_limits(
-        &self,
-        _gas_limit: f64,
-        _time_limit: Duration,
-    ) -> Result<GasPrice1559> {
-        let legacy = self
-            .eth()
-            .gas_price()
-            .await
-            .context("failed to get web3 gas price")
-            .map(U256::to_f64_lossy)?;
-
-        Ok(GasPrice1559 {
-            base_fee_per_gas: 0.0,
-            max_fee_per_gas: legacy,
-            max_priority_fee_per_gas: legacy,
-        })
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
+        self.estimate_with_request(
+            EstimationRequest::default()
+                .with_gas_limit(gas_limit)
+                .with_time_limit(time_limit),
+        )
+        .await
+    }
+
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        Ok(detailed_estimate(self, &Default::default(), request)
+            .await?
+            .gas_price)
+    }
+
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        detailed_estimate(self, &Default::default(), request).await
+    }
+}
+
+async fn detailed_estimate<T>(
+    web3: &Web3<T>,
+    params: &Params,
+    request: EstimationRequest,
+) -> Result<Estimate>
+where
+    T: Transport + Send + Sync,
+    <T as Transport>::Out: Send,
+{
+    let (gas_price, block_number) = node_estimate(web3, params, &request).await?;
+    let estimate = Estimate::new(request.apply(gas_price)?, "eth_node").with_age(Duration::ZERO);
+    Ok(match block_number {
+        Some(block_number) => estimate.with_block_number(block_number),
+        None => estimate,
+    })
+}
+
+// The estimate for the deadline of `request` together with the number of the latest block it is
+// based on.
+async fn node_estimate<T>(
+    web3: &Web3<T>,
+    params: &Params,
+    request: &EstimationRequest,
+) -> Result<(GasPrice, Option<u64>)>
+where
+    T: Transport + Send + Sync,
+    <T as Transport>::Out: Send,
+{
+    let latest = web3
+        .eth()
+        .block(BlockNumber::Latest.into())
+        .await?
+        .ok_or_else(|| Error::parse("missing latest block"))?;
+    let block_number = latest.number.map(|number| number.as_u64());
+    let base_fee = match pending_base_fee(&latest, &params.base_fee) {
+        Some(base_fee) => base_fee,
+        // pre-London
+        None => {
+            let gas_price = web3.eth().gas_price().await?;
+            return Ok((GasPrice::Legacy { gas_price }, block_number));
+        }
+    };
+    let priority_fee = match max_priority_fee_per_gas(web3).await {
+        Ok(priority_fee) => priority_fee,
+        Err(err) => {
+            tracing::debug!(?err, "eth_maxPriorityFeePerGas failed, using eth_gasPrice");
+            web3.eth().gas_price().await?.saturating_sub(base_fee)
+        }
+    };
+    let gas_price = GasPrice1559 {
+        base_fee_per_gas: base_fee,
+        max_fee_per_gas: params
+            .base_fee
+            .max_base_fee_after(base_fee, deadline_blocks(request, block_number, params))
+            .saturating_add(priority_fee),
+        max_priority_fee_per_gas: priority_fee,
+    };
+    Ok((gas_price.validate()?.into(), block_number))
+}
+
+async fn max_priority_fee_per_gas<T: Transport>(web3: &Web3<T>) -> Result<U256> {
+    let transport = web3.transport();
+    Ok(CallFuture::new(transport.execute("eth_maxPriorityFeePerGas", vec![])).await?)
+}
+
+// Number of blocks after the next one until the deadline of `request`, the next block being the
+// first one the transaction can be included in.
+fn deadline_blocks(request: &EstimationRequest, latest: Option<u64>, params: &Params) -> u64 {
+    let blocks = match (request.target_block, latest) {
+        (Some(target_block), Some(latest)) => target_block.saturating_sub(latest) as f64,
+        _ if params.block_time.is_zero() => 1.0,
+        _ => request.time_limit.as_secs_f64() / params.block_time.as_secs_f64(),
+    };
+    ((blocks.ceil() - 1.0).max(0.0) as u64).min(params.max_fee_blocks)
+}
+
+// Base fee of the block after `block`, None if `block` has no base fee.
+fn pending_base_fee(block: &Block<H256>, params: &base_fee::Params) -> Option<U256> {
+    let base_fee = block.base_fee_per_gas?;
+    Some(params.next_base_fee(base_fee, block.gas_used, block.gas_limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Web3TestTransport;
+    use futures::FutureExt;
+
+    fn block(base_fee: Option<u64>, gas_used: u64) -> Block<H256> {
+        Block {
+            base_fee_per_gas: base_fee.map(U256::from),
+            gas_used: gas_used.into(),
+            gas_limit: 30_000_000u64.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pending_base_fee_follows_gas_used() {
+        let pending_base_fee = |block| pending_base_fee(&block, &base_fee::Params::MAINNET);
+        assert_eq!(pending_base_fee(block(None, 0)), None);
+        assert_eq!(
+            pending_base_fee(block(Some(800), 15_000_000)),
+            Some(800.into())
+        );
+        assert_eq!(
+            pending_base_fee(block(Some(800), 30_000_000)),
+            Some(900.into())
+        );
+        assert_eq!(pending_base_fee(block(Some(800), 0)), Some(700.into()));
+        // increases by at least 1 wei
+        assert_eq!(pending_base_fee(block(Some(7), 15_000_001)), Some(8.into()));
+    }
+
+    #[test]
+    fn estimates_with_chain_params() {
+        let transport = Web3TestTransport::default();
+        transport.add_response(
+            "eth_getBlockByNumber",
+            serde_json::to_value(Block {
+                number: Some(1.into()),
+                ..block(Some(1000), 30_000_000)
+            })
+            .unwrap(),
+        );
+        transport.add_response("eth_maxPriorityFeePerGas", serde_json::json!("0x2"));
+        let web3 = Web3::new(transport);
+
+        let optimism = EthNode::new(
+            web3.clone(),
+            Params {
+                base_fee: base_fee::Params::OPTIMISM,
+                block_time: Duration::from_secs(2),
+                max_fee_blocks: 1,
+            },
+        );
+        let estimate = optimism
+            .estimate_detailed(Default::default())
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            estimate.gas_price,
+            GasPrice::from(GasPrice1559 {
+                base_fee_per_gas: 1020.into(),
+                max_fee_per_gas: 1042.into(),
+                max_priority_fee_per_gas: 2.into(),
+            })
+        );
+        assert_eq!(estimate.block_number, Some(1));
+
+        // the next block and three more
+        let gas_price = web3
+            .estimate_with_request(EstimationRequest::default().with_target_block(5))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            gas_price.to_eip1559().max_fee_per_gas,
+            base_fee::Params::MAINNET.max_base_fee_after(1125.into(), 3) + 2
+        );
+        let gas_price = optimism
+            .estimate_with_request(EstimationRequest::default().with_target_block(5))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        // capped at one block
+        assert_eq!(gas_price.to_eip1559().max_fee_per_gas, 1042.into());
+
+        // 30 s are 2.5 blocks, the next block and two more
+        let gas_price = web3.estimate().now_or_never().unwrap().unwrap();
+        assert_eq!(gas_price.to_eip1559().base_fee_per_gas, 1125.into());
+        assert_eq!(
+            gas_price.to_eip1559().max_fee_per_gas,
+            base_fee::Params::MAINNET.max_base_fee_after(1125.into(), 2) + 2
+        );
+    }
+
+    #[test]
+    fn deadline_blocks_follow_request() {
+        let params = Params::default();
+        let blocks =
+            |request: EstimationRequest, latest| deadline_blocks(&request, latest, &params);
+        let time_limit =
+            |secs| EstimationRequest::default().with_time_limit(Duration::from_secs(secs));
+        assert_eq!(blocks(time_limit(0), Some(10)), 0);
+        assert_eq!(blocks(time_limit(12), Some(10)), 0);
+        assert_eq!(blocks(time_limit(13), Some(10)), 1);
+        assert_eq!(blocks(time_limit(600), Some(10)), 49);
+        assert_eq!(blocks(time_limit(3600), Some(10)), 127);
+        let target_block = |block| EstimationRequest::default().with_target_block(block);
+        assert_eq!(blocks(target_block(11), Some(10)), 0);
+        assert_eq!(blocks(target_block(15), Some(10)), 4);
+        assert_eq!(blocks(target_block(5), Some(10)), 0);
+        // without the latest block number the time limit is used
+        assert_eq!(blocks(target_block(15), None), 2);
     }
 }
@@ -1,26 +1,27 @@
-use anyhow::{anyhow, Result};
+use crate::{Error, Result};
+use primitive_types::{U256, U512};
 use serde::Serialize;
+use std::convert::TryFrom;
 
 /// EIP1559 gas price
-#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Serialize)]
 pub struct GasPrice1559 {
     // Estimated base fee for the pending block (block currently being mined)
-    pub base_fee_per_gas: f64,
+    pub base_fee_per_gas: U256,
     // Maximum gas price willing to pay for the transaction.
-    pub max_fee_per_gas: f64,
+    pub max_fee_per_gas: U256,
     // Priority fee used to incentivize miners to include the tx in case of network congestion.
-    pub max_priority_fee_per_gas: f64,
+    pub max_priority_fee_per_gas: U256,
 }
 
 impl GasPrice1559 {
     // Estimate the effective gas price based on the current network conditions (base_fee_per_gas)
     // Beware that gas price for mined transaction could be different from estimated value in case of 1559 tx
     // (because base_fee_per_gas can change between estimation and mining the tx).
-    pub fn effective_gas_price(&self) -> f64 {
-        std::cmp::min_by(
-            self.max_fee_per_gas,
-            self.max_priority_fee_per_gas + self.base_fee_per_gas,
-            |a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal),
+    pub fn effective_gas_price(&self) -> U256 {
+        self.max_fee_per_gas.min(
+            self.max_priority_fee_per_gas
+                .saturating_add(self.base_fee_per_gas),
         )
     }
 
@@ -36,29 +37,41 @@ impl GasPrice1559 {
     pub fn validate(self) -> Result<Self> {
         match self.is_valid() {
             true => Ok(self),
-            false => Err(anyhow!("invalid gas price values: {:?}", self)),
+            false => Err(Error::Validation(format!(
+                "invalid gas price values: {:?}",
+                self
+            ))),
         }
     }
-    // Bump gas price by factor.
+
+    // Bump gas price by factor. The result is rounded up to the next wei so that a bump never ends
+    // up below the exact product.
     pub fn bump(self, factor: f64) -> Self {
         Self {
-            max_fee_per_gas: self.max_fee_per_gas * factor,
-            max_priority_fee_per_gas: self.max_priority_fee_per_gas * factor,
+            max_fee_per_gas: mul_f64_ceil(self.max_fee_per_gas, factor),
+            max_priority_fee_per_gas: mul_f64_ceil(self.max_priority_fee_per_gas, factor),
             ..self
         }
     }
 
-    // Ceil gas price (since its defined as float).
+    // Ceil gas price. Values are integer amounts of wei and `bump` already rounds up, so this is a
+    // no-op kept for compatibility with callers written against the floating point representation.
     pub fn ceil(self) -> Self {
-        Self {
-            max_fee_per_gas: self.max_fee_per_gas.ceil(),
-            max_priority_fee_per_gas: self.max_priority_fee_per_gas.ceil(),
-            ..self
-        }
+        self
+    }
+
+    /// Lowest gas price that replaces a pending transaction priced at `previous` according to
+    /// `rules` and is at least `fresh_estimate`.
+    pub fn replacement_for(
+        previous: GasPrice1559,
+        fresh_estimate: GasPrice1559,
+        rules: &ReplacementRules,
+    ) -> Self {
+        replace(previous, fresh_estimate, rules.min_bump_percent)
     }
 
     // If current cap if higher then the input, set to input.
-    pub fn limit_cap(self, cap: f64) -> Self {
+    pub fn limit_cap(self, cap: U256) -> Self {
         Self {
             max_fee_per_gas: self.max_fee_per_gas.min(cap),
             max_priority_fee_per_gas: self
@@ -71,14 +84,6 @@ impl GasPrice1559 {
 
 impl std::fmt::Display for GasPrice1559 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let format_unit = |wei| {
-            let gwei: f64 = wei / 1e9;
-            if gwei >= 1.0 {
-                format!("{:.2} Gwei", gwei)
-            } else {
-                format!("{wei} wei")
-            }
-        };
         write!(
             f,
             "{{ max_fee: {}, max_priority_fee: {}, base_fee: {} }}",
@@ -89,99 +94,602 @@ impl std::fmt::Display for GasPrice1559 {
     }
 }
 
+/// Minimum fee increases nodes require to replace a pending transaction with one of the same nonce.
+///
+/// The default matches the transaction pool of go-ethereum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplacementRules {
+    // minimum increase in percent of the max fee and max priority fee per gas, the gas price of
+    // legacy transactions
+    pub min_bump_percent: u64,
+    // minimum increase in percent of all fees of blob transactions, including the max fee per blob
+    // gas
+    pub min_blob_bump_percent: u64,
+}
+
+impl Default for ReplacementRules {
+    fn default() -> Self {
+        Self {
+            min_bump_percent: 10,
+            min_blob_bump_percent: 100,
+        }
+    }
+}
+
+impl ReplacementRules {
+    pub fn with_min_bump_percent(self, min_bump_percent: u64) -> Self {
+        Self {
+            min_bump_percent,
+            ..self
+        }
+    }
+
+    pub fn with_min_blob_bump_percent(self, min_blob_bump_percent: u64) -> Self {
+        Self {
+            min_blob_bump_percent,
+            ..self
+        }
+    }
+}
+
+// Replacement of `previous` bumped by `percent` that is at least `fresh_estimate`.
+fn replace(previous: GasPrice1559, fresh_estimate: GasPrice1559, percent: u64) -> GasPrice1559 {
+    let max_priority_fee_per_gas = bump_percent(previous.max_priority_fee_per_gas, percent)
+        .max(fresh_estimate.max_priority_fee_per_gas);
+    GasPrice1559 {
+        base_fee_per_gas: fresh_estimate.base_fee_per_gas,
+        max_fee_per_gas: bump_percent(previous.max_fee_per_gas, percent)
+            .max(fresh_estimate.max_fee_per_gas)
+            .max(max_priority_fee_per_gas),
+        max_priority_fee_per_gas,
+    }
+}
+
+// Smallest value that nodes accept as an increase of `value` by `percent`, which is the exact
+// increase rounded up.
+fn bump_percent(value: U256, percent: u64) -> U256 {
+    let bumped = value.full_mul(U256::from(100) + U256::from(percent));
+    let bumped = (bumped + U512::from(99u64)) / U512::from(100u64);
+    U256::try_from(bumped).unwrap_or(U256::MAX)
+}
+
+/// Fee model of a gas price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeeModel {
+    Legacy,
+    Eip1559,
+    Eip4844,
+}
+
+/// Gas price for one of the supported transaction fee models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum GasPrice {
+    /// Pre EIP1559 pricing with a single gas price.
+    Legacy { gas_price: U256 },
+    /// EIP1559 pricing.
+    Eip1559(GasPrice1559),
+    /// EIP1559 pricing of the execution gas plus the maximum fee per blob gas of an EIP4844
+    /// blob-carrying transaction.
+    Eip4844 {
+        fee: GasPrice1559,
+        max_fee_per_blob_gas: U256,
+    },
+}
+
+impl Default for GasPrice {
+    fn default() -> Self {
+        Self::Eip1559(Default::default())
+    }
+}
+
+impl From<GasPrice1559> for GasPrice {
+    fn from(fee: GasPrice1559) -> Self {
+        Self::Eip1559(fee)
+    }
+}
+
+impl GasPrice {
+    pub fn fee_model(&self) -> FeeModel {
+        match self {
+            Self::Legacy { .. } => FeeModel::Legacy,
+            Self::Eip1559(_) => FeeModel::Eip1559,
+            Self::Eip4844 { .. } => FeeModel::Eip4844,
+        }
+    }
+
+    // Convert to EIP1559 pricing. A legacy gas price becomes a 1559 price that pays exactly the
+    // legacy gas price (max_fee_per_gas == max_priority_fee_per_gas), the blob fee of a 4844 price
+    // is dropped.
+    pub fn to_eip1559(self) -> GasPrice1559 {
+        match self {
+            Self::Legacy { gas_price } => GasPrice1559 {
+                base_fee_per_gas: U256::zero(),
+                max_fee_per_gas: gas_price,
+                max_priority_fee_per_gas: gas_price,
+            },
+            Self::Eip1559(fee) | Self::Eip4844 { fee, .. } => fee,
+        }
+    }
+
+    // Convert to a legacy gas price. For 1559 prices this is the effective gas price since a legacy
+    // transaction pays its whole gas price.
+    pub fn to_legacy(self) -> U256 {
+        match self {
+            Self::Legacy { gas_price } => gas_price,
+            Self::Eip1559(fee) | Self::Eip4844 { fee, .. } => fee.effective_gas_price(),
+        }
+    }
+
+    // Convert to EIP4844 pricing with the given max fee per blob gas.
+    pub fn with_blob_fee(self, max_fee_per_blob_gas: U256) -> Self {
+        Self::Eip4844 {
+            fee: self.to_eip1559(),
+            max_fee_per_blob_gas,
+        }
+    }
+
+    pub fn max_fee_per_blob_gas(&self) -> Option<U256> {
+        match self {
+            Self::Eip4844 {
+                max_fee_per_blob_gas,
+                ..
+            } => Some(*max_fee_per_blob_gas),
+            _ => None,
+        }
+    }
+
+    pub fn effective_gas_price(&self) -> U256 {
+        self.to_legacy()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Legacy { .. } => true,
+            Self::Eip1559(fee) | Self::Eip4844 { fee, .. } => fee.is_valid(),
+        }
+    }
+
+    // Validate and build Result based on the validation result
+    pub fn validate(self) -> Result<Self> {
+        match self.is_valid() {
+            true => Ok(self),
+            false => Err(Error::Validation(format!(
+                "invalid gas price values: {:?}",
+                self
+            ))),
+        }
+    }
+
+    // Bump all fee caps by factor, including the blob fee.
+    pub fn bump(self, factor: f64) -> Self {
+        self.map(|fee| fee.bump(factor), |fee| mul_f64_ceil(fee, factor))
+    }
+
+    pub fn ceil(self) -> Self {
+        self
+    }
+
+    /// Lowest gas price that replaces a pending transaction priced at `previous` according to
+    /// `rules` and is at least `fresh_estimate`.
+    ///
+    /// The replacement has the fee model of `previous`. Blob transactions need the larger blob bump
+    /// on all fees.
+    pub fn replacement_for(
+        previous: GasPrice,
+        fresh_estimate: GasPrice,
+        rules: &ReplacementRules,
+    ) -> Self {
+        match previous {
+            Self::Legacy { gas_price } => Self::Legacy {
+                gas_price: bump_percent(gas_price, rules.min_bump_percent)
+                    .max(fresh_estimate.to_legacy()),
+            },
+            Self::Eip1559(fee) => Self::Eip1559(GasPrice1559::replacement_for(
+                fee,
+                fresh_estimate.to_eip1559(),
+                rules,
+            )),
+            Self::Eip4844 {
+                fee,
+                max_fee_per_blob_gas,
+            } => Self::Eip4844 {
+                fee: replace(
+                    fee,
+                    fresh_estimate.to_eip1559(),
+                    rules.min_blob_bump_percent,
+                ),
+                max_fee_per_blob_gas: bump_percent(
+                    max_fee_per_blob_gas,
+                    rules.min_blob_bump_percent,
+                )
+                .max(fresh_estimate.max_fee_per_blob_gas().unwrap_or_default()),
+            },
+        }
+    }
+
+    // Cap the execution gas fees. The blob fee is not affected.
+    pub fn limit_cap(self, cap: U256) -> Self {
+        self.map(|fee| fee.limit_cap(cap), |fee| fee)
+    }
+
+    fn map(self, gas: impl Fn(GasPrice1559) -> GasPrice1559, blob: impl Fn(U256) -> U256) -> Self {
+        match self {
+            Self::Legacy { gas_price } => Self::Legacy {
+                gas_price: gas(GasPrice::Legacy { gas_price }.to_eip1559()).max_fee_per_gas,
+            },
+            Self::Eip1559(fee) => Self::Eip1559(gas(fee)),
+            Self::Eip4844 {
+                fee,
+                max_fee_per_blob_gas,
+            } => Self::Eip4844 {
+                fee: gas(fee),
+                max_fee_per_blob_gas: blob(max_fee_per_blob_gas),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for GasPrice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Legacy { gas_price } => write!(f, "{{ gas_price: {} }}", format_unit(*gas_price)),
+            Self::Eip1559(fee) => fee.fmt(f),
+            Self::Eip4844 {
+                fee,
+                max_fee_per_blob_gas,
+            } => write!(
+                f,
+                "{{ max_fee: {}, max_priority_fee: {}, base_fee: {}, max_fee_per_blob_gas: {} }}",
+                format_unit(fee.max_fee_per_gas),
+                format_unit(fee.max_priority_fee_per_gas),
+                format_unit(fee.base_fee_per_gas),
+                format_unit(*max_fee_per_blob_gas),
+            ),
+        }
+    }
+}
+
+fn format_unit(wei: U256) -> String {
+    if wei >= U256::exp10(9) {
+        format!("{:.2} Gwei", wei.to_f64_lossy() / 1e9)
+    } else {
+        format!("{wei} wei")
+    }
+}
+
+/// Converts a floating point amount of wei, as reported by external APIs, into an integer amount
+/// of wei rounded to the nearest integer. Fails for negative, non finite or overflowing values
+/// instead of silently truncating them.
+pub fn wei_from_f64(value: f64) -> Result<U256> {
+    if !value.is_finite() {
+        return Err(Error::Validation(format!(
+            "wei value {} is not finite",
+            value
+        )));
+    }
+    if value < 0.0 {
+        return Err(Error::Validation(format!(
+            "wei value {} is negative",
+            value
+        )));
+    }
+    let value = value.round();
+    if value >= 2f64.powi(256) {
+        return Err(Error::Validation(format!(
+            "wei value {} overflows U256",
+            value
+        )));
+    }
+    Ok(U256::from_f64_lossy(value))
+}
+
+/// Multiplies `value` by `factor` rounding the result down.
+///
+/// The factor is decomposed into its exact binary representation (mantissa * 2^exponent) so the
+/// product is computed in integer arithmetic without going through a lossy float. Negative and NaN
+/// factors are treated as zero and results that do not fit into U256 saturate.
+pub(crate) fn mul_f64_floor(value: U256, factor: f64) -> U256 {
+    mul_f64(value, factor, false)
+}
+
+/// Same as `mul_f64_floor` but rounds the result up.
+pub(crate) fn mul_f64_ceil(value: U256, factor: f64) -> U256 {
+    mul_f64(value, factor, true)
+}
+
+fn mul_f64(value: U256, factor: f64, round_up: bool) -> U256 {
+    if factor.is_nan() || factor <= 0.0 {
+        return U256::zero();
+    }
+    if factor.is_infinite() {
+        return if value.is_zero() { value } else { U256::MAX };
+    }
+
+    let bits = factor.to_bits();
+    let exponent_bits = ((bits >> 52) & 0x7ff) as i32;
+    let fraction = bits & ((1 << 52) - 1);
+    let (mantissa, exponent) = if exponent_bits == 0 {
+        (fraction, -1074)
+    } else {
+        (fraction | (1 << 52), exponent_bits - 1075)
+    };
+
+    let product = value.full_mul(U256::from(mantissa));
+    let result = if exponent >= 0 {
+        let exponent = exponent as usize;
+        if product.is_zero() {
+            product
+        } else if product.bits() + exponent > 256 {
+            return U256::MAX;
+        } else {
+            product << exponent
+        }
+    } else {
+        let shift = (-exponent) as usize;
+        if shift >= 512 {
+            let round = round_up && !product.is_zero();
+            return if round { U256::one() } else { U256::zero() };
+        }
+        let quotient = product >> shift;
+        let remainder = product - (quotient << shift);
+        if round_up && !remainder.is_zero() {
+            quotient + U512::one()
+        } else {
+            quotient
+        }
+    };
+    U256::try_from(result).unwrap_or(U256::MAX)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::GasPrice1559;
-    use assert_approx_eq::assert_approx_eq;
+    use super::*;
 
     #[test]
     fn bump_and_ceil() {
         let gas_price = GasPrice1559 {
-            max_fee_per_gas: 2.0,
-            max_priority_fee_per_gas: 3.0,
+            max_fee_per_gas: 2.into(),
+            max_priority_fee_per_gas: 3.into(),
             ..Default::default()
         };
 
         let gas_price_bumped = GasPrice1559 {
-            max_fee_per_gas: 2.25,
-            max_priority_fee_per_gas: 3.375,
+            max_fee_per_gas: 3.into(),
+            max_priority_fee_per_gas: 4.into(),
             ..Default::default()
         };
 
-        let gas_price_bumped_and_ceiled = GasPrice1559 {
-            max_fee_per_gas: 3.0,
-            max_priority_fee_per_gas: 4.0,
+        assert_eq!(gas_price.bump(1.125), gas_price_bumped);
+        assert_eq!(gas_price.bump(1.125).ceil(), gas_price_bumped);
+    }
+
+    #[test]
+    fn bump_is_exact_for_large_values() {
+        let gas_price = GasPrice1559 {
+            max_fee_per_gas: U256::exp10(40) + 1,
+            max_priority_fee_per_gas: U256::exp10(30),
             ..Default::default()
         };
 
-        assert_eq!(gas_price.bump(1.125), gas_price_bumped);
-        assert_eq!(gas_price.bump(1.125).ceil(), gas_price_bumped_and_ceiled);
+        let bumped = gas_price.bump(1.125);
+        assert_eq!(
+            bumped.max_fee_per_gas,
+            U256::from(1125) * U256::exp10(37) + 2
+        );
+        assert_eq!(
+            bumped.max_priority_fee_per_gas,
+            U256::from(1125) * U256::exp10(27)
+        );
+    }
+
+    #[test]
+    fn mul_f64_rounding() {
+        assert_eq!(mul_f64_floor(10.into(), 0.25), 2.into());
+        assert_eq!(mul_f64_ceil(10.into(), 0.25), 3.into());
+        assert_eq!(mul_f64_floor(10.into(), 2.0), 20.into());
+        assert_eq!(mul_f64_floor(10.into(), 0.0), 0.into());
+        assert_eq!(mul_f64_floor(10.into(), -1.0), 0.into());
+        assert_eq!(mul_f64_floor(10.into(), f64::NAN), 0.into());
+        assert_eq!(mul_f64_floor(U256::MAX, 2.0), U256::MAX);
+        assert_eq!(mul_f64_ceil(1.into(), f64::MIN_POSITIVE), 1.into());
+    }
+
+    #[test]
+    fn wei_from_f64_checks_input() {
+        assert_eq!(
+            wei_from_f64(94647990462.00001).unwrap(),
+            94647990462u64.into()
+        );
+        assert!(wei_from_f64(-1.0).is_err());
+        assert!(wei_from_f64(f64::NAN).is_err());
+        assert!(wei_from_f64(f64::INFINITY).is_err());
+        assert!(wei_from_f64(1e80).is_err());
     }
 
     #[test]
     fn limit_cap_only_max_fee_capped() {
         let gas_price = GasPrice1559 {
-            max_fee_per_gas: 5.0,
-            max_priority_fee_per_gas: 3.0,
+            max_fee_per_gas: 5.into(),
+            max_priority_fee_per_gas: 3.into(),
             ..Default::default()
         };
 
         let gas_price_capped = GasPrice1559 {
-            max_fee_per_gas: 4.0,
-            max_priority_fee_per_gas: 3.0,
+            max_fee_per_gas: 4.into(),
+            max_priority_fee_per_gas: 3.into(),
             ..Default::default()
         };
 
-        assert_eq!(gas_price.limit_cap(4.0), gas_price_capped);
+        assert_eq!(gas_price.limit_cap(4.into()), gas_price_capped);
     }
 
     #[test]
     fn limit_cap_max_fee_and_max_priority_capped() {
         let gas_price = GasPrice1559 {
-            max_fee_per_gas: 5.0,
-            max_priority_fee_per_gas: 3.0,
+            max_fee_per_gas: 5.into(),
+            max_priority_fee_per_gas: 3.into(),
             ..Default::default()
         };
 
         let gas_price_capped = GasPrice1559 {
-            max_fee_per_gas: 2.0,
-            max_priority_fee_per_gas: 2.0,
+            max_fee_per_gas: 2.into(),
+            max_priority_fee_per_gas: 2.into(),
             ..Default::default()
         };
 
-        assert_eq!(gas_price.limit_cap(2.0), gas_price_capped);
+        assert_eq!(gas_price.limit_cap(2.into()), gas_price_capped);
+    }
+
+    #[test]
+    fn legacy_conversions() {
+        let legacy = GasPrice::Legacy {
+            gas_price: 10.into(),
+        };
+        assert_eq!(legacy.fee_model(), FeeModel::Legacy);
+        assert_eq!(
+            legacy.to_eip1559(),
+            GasPrice1559 {
+                base_fee_per_gas: 0.into(),
+                max_fee_per_gas: 10.into(),
+                max_priority_fee_per_gas: 10.into(),
+            }
+        );
+        assert_eq!(legacy.to_legacy(), 10.into());
+        assert_eq!(
+            legacy.bump(1.5),
+            GasPrice::Legacy {
+                gas_price: 15.into()
+            }
+        );
+    }
+
+    #[test]
+    fn eip1559_conversions() {
+        let fee = GasPrice1559 {
+            base_fee_per_gas: 2.into(),
+            max_fee_per_gas: 10.into(),
+            max_priority_fee_per_gas: 5.into(),
+        };
+        let price = GasPrice::from(fee);
+        assert_eq!(price.fee_model(), FeeModel::Eip1559);
+        assert_eq!(price.to_eip1559(), fee);
+        assert_eq!(price.to_legacy(), 7.into());
+        assert_eq!(price.max_fee_per_blob_gas(), None);
+
+        let blob = price.with_blob_fee(3.into());
+        assert_eq!(blob.fee_model(), FeeModel::Eip4844);
+        assert_eq!(blob.to_eip1559(), fee);
+        assert_eq!(blob.max_fee_per_blob_gas(), Some(3.into()));
+        assert_eq!(blob.bump(2.0).max_fee_per_blob_gas(), Some(6.into()));
+        assert_eq!(
+            blob.limit_cap(4.into()).max_fee_per_blob_gas(),
+            Some(3.into())
+        );
+    }
+
+    #[test]
+    fn replacement_bumps_previous_price() {
+        let previous = GasPrice1559 {
+            base_fee_per_gas: 50.into(),
+            max_fee_per_gas: 100.into(),
+            max_priority_fee_per_gas: 5.into(),
+        };
+        let fresh_estimate = GasPrice1559 {
+            base_fee_per_gas: 40.into(),
+            max_fee_per_gas: 90.into(),
+            max_priority_fee_per_gas: 2.into(),
+        };
+        let rules = ReplacementRules::default();
+        // 10% rounded up
+        assert_eq!(
+            GasPrice1559::replacement_for(previous, fresh_estimate, &rules),
+            GasPrice1559 {
+                base_fee_per_gas: 40.into(),
+                max_fee_per_gas: 110.into(),
+                max_priority_fee_per_gas: 6.into(),
+            }
+        );
+        // at least the fresh estimate
+        let fresh_estimate = GasPrice1559 {
+            max_fee_per_gas: 200.into(),
+            ..fresh_estimate
+        };
+        assert_eq!(
+            GasPrice1559::replacement_for(previous, fresh_estimate, &rules),
+            GasPrice1559 {
+                base_fee_per_gas: 40.into(),
+                max_fee_per_gas: 200.into(),
+                max_priority_fee_per_gas: 6.into(),
+            }
+        );
+        let rules = rules.with_min_bump_percent(25);
+        assert_eq!(
+            GasPrice1559::replacement_for(previous, previous, &rules).max_fee_per_gas,
+            125.into()
+        );
+    }
+
+    #[test]
+    fn replacement_of_blob_and_legacy_prices() {
+        let fee = GasPrice1559 {
+            base_fee_per_gas: 50.into(),
+            max_fee_per_gas: 100.into(),
+            max_priority_fee_per_gas: 5.into(),
+        };
+        let rules = ReplacementRules::default();
+
+        let blob = GasPrice::from(fee).with_blob_fee(7.into());
+        let replacement = GasPrice::replacement_for(blob, fee.into(), &rules);
+        assert_eq!(replacement.max_fee_per_blob_gas(), Some(14.into()));
+        assert_eq!(replacement.to_eip1559().max_fee_per_gas, 200.into());
+        assert_eq!(replacement.to_eip1559().max_priority_fee_per_gas, 10.into());
+        let fresh_estimate = GasPrice::from(fee).with_blob_fee(20.into());
+        assert_eq!(
+            GasPrice::replacement_for(blob, fresh_estimate, &rules).max_fee_per_blob_gas(),
+            Some(20.into())
+        );
+
+        let legacy = GasPrice::Legacy {
+            gas_price: 100.into(),
+        };
+        assert_eq!(
+            GasPrice::replacement_for(legacy, fee.into(), &rules),
+            GasPrice::Legacy {
+                gas_price: 110.into()
+            }
+        );
     }
 
     #[test]
     fn estimate_eip1559() {
-        assert_approx_eq!(
+        assert_eq!(
             GasPrice1559 {
-                max_fee_per_gas: 10.0,
-                max_priority_fee_per_gas: 5.0,
-                base_fee_per_gas: 2.0
+                max_fee_per_gas: 10.into(),
+                max_priority_fee_per_gas: 5.into(),
+                base_fee_per_gas: 2.into()
             }
             .effective_gas_price(),
-            7.0
+            7.into()
         );
 
-        assert_approx_eq!(
+        assert_eq!(
             GasPrice1559 {
-                max_fee_per_gas: 10.0,
-                max_priority_fee_per_gas: 8.0,
-                base_fee_per_gas: 2.0
+                max_fee_per_gas: 10.into(),
+                max_priority_fee_per_gas: 8.into(),
+                base_fee_per_gas: 2.into()
             }
             .effective_gas_price(),
-            10.0
+            10.into()
         );
 
-        assert_approx_eq!(
+        assert_eq!(
             GasPrice1559 {
-                max_fee_per_gas: 10.0,
-                max_priority_fee_per_gas: 10.0,
-                base_fee_per_gas: 2.0
+                max_fee_per_gas: 10.into(),
+                max_priority_fee_per_gas: 10.into(),
+                base_fee_per_gas: 2.into()
             }
             .effective_gas_price(),
-            10.0
+            10.into()
         );
     }
 }
@@ -1,5 +1,7 @@
-use super::{linear_interpolation, GasPrice1559, GasPriceEstimating, Transport};
-use anyhow::{anyhow, Context, Result};
+use super::{
+    gas_price::wei_from_f64, linear_interpolation, GasPrice, GasPriceEstimating, Transport,
+};
+use crate::{Error, Result};
 use futures::lock::Mutex;
 use std::{
     convert::TryInto,
@@ -12,16 +14,19 @@ use std::{
 const API_URI: &str = "https://etherchain.org/api/gasnow";
 const RATE_LIMIT: Duration = Duration::from_secs(15);
 
+/// Produces `GasPrice::Legacy` estimates.
 pub struct GasNowGasStation<T> {
     transport: T,
+    // minimum time between two requests
+    rate_limit: Duration,
     last_response: Mutex<Option<CachedResponse>>,
 }
 
 struct CachedResponse {
     // The time at which the request was sent.
     time: Instant,
-    // The result of the last response. Error isn't Clone so we store None in the error case.
-    data: Option<Response>,
+    // The result of the last response.
+    data: Result<Response>,
 }
 
 #[derive(Clone, Copy, Debug, Default, serde::Deserialize, PartialEq)]
@@ -48,7 +53,7 @@ pub fn estimate_with_limits(
     _gas_limit: f64,
     time_limit: Duration,
     response: &ResponseData,
-) -> Result<GasPrice1559> {
+) -> Result<GasPrice> {
     let points: &[(f64, f64)] = &[
         (RAPID.as_secs_f64(), response.rapid),
         (FAST.as_secs_f64(), response.fast),
@@ -56,27 +61,29 @@ pub fn estimate_with_limits(
         (SLOW.as_secs_f64(), response.slow),
     ];
 
-    let legacy = linear_interpolation::interpolate(time_limit.as_secs_f64(), points.try_into()?);
-    Ok(GasPrice1559 {
-        base_fee_per_gas: 0.0,
-        max_fee_per_gas: legacy,
-        max_priority_fee_per_gas: legacy,
-    })
+    let legacy = wei_from_f64(linear_interpolation::interpolate(
+        time_limit.as_secs_f64(),
+        points.try_into()?,
+    ))?;
+    Ok(GasPrice::Legacy { gas_price: legacy })
 }
 
 impl<T: Transport> GasNowGasStation<T> {
+    /// Makes at most one request every 15 s.
     pub fn new(transport: T) -> Self {
         Self {
             transport,
+            rate_limit: RATE_LIMIT,
             last_response: Default::default(),
         }
     }
 
+    pub fn with_rate_limit(self, rate_limit: Duration) -> Self {
+        Self { rate_limit, ..self }
+    }
+
     async fn gas_price_without_cache(&self) -> Result<Response> {
-        self.transport
-            .get_json(API_URI, Default::default())
-            .await
-            .context("failed to get gasnow gas price")
+        self.transport.get_json(API_URI, Default::default()).await
     }
 
     // Ensures that no requests are made faster than the rate limit by caching the previous
@@ -94,19 +101,14 @@ impl<T: Transport> GasNowGasStation<T> {
         // checked_duration_since to catch this.
         let mut lock = self.last_response.lock().await;
         match lock.as_ref() {
-            Some(cached) if now.saturating_duration_since(cached.time) < RATE_LIMIT => {
-                match cached.data {
-                    Some(response) => Ok(response),
-                    None => Err(anyhow!(
-                        "previous gasnow response was error and cache has not yet expired"
-                    )),
-                }
+            Some(cached) if now.saturating_duration_since(cached.time) < self.rate_limit => {
+                cached.data.as_ref().copied().map_err(Error::duplicate)
             }
             _ => {
                 let result = fetch().await;
                 *lock = Some(CachedResponse {
                     time: now,
-                    data: result.as_ref().ok().copied(),
+                    data: result.as_ref().copied().map_err(Error::duplicate),
                 });
                 result
             }
@@ -116,11 +118,7 @@ impl<T: Transport> GasNowGasStation<T> {
 
 #[async_trait::async_trait]
 impl<T: Transport> GasPriceEstimating for GasNowGasStation<T> {
-    async fn estimate_with_limits(
-        &self,
-        gas_limit: f64,
-        time_limit: Duration,
-    ) -> Result<GasPrice1559> {
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
         let response = self
             .gas_price_with_cache(Instant::now(), || self.gas_price_without_cache())
             .await?
@@ -133,6 +131,7 @@ impl<T: Transport> GasPriceEstimating for GasNowGasStation<T> {
 mod tests {
     use super::super::tests::{FutureWaitExt as _, TestTransport};
     use super::*;
+    use crate::FeeModel;
     use futures::FutureExt;
     use std::future::{ready, Pending};
 
@@ -143,13 +142,15 @@ mod tests {
     #[test]
     fn interpolates() {
         let data = ResponseData {
-            rapid: 4.0,
-            fast: 3.0,
-            standard: 2.0,
-            slow: 1.0,
+            rapid: 4e9,
+            fast: 3e9,
+            standard: 2e9,
+            slow: 1e9,
         };
         let result = estimate_with_limits(0., Duration::from_secs(20), &data).unwrap();
-        assert!(result.max_fee_per_gas > 3.0 && result.max_fee_per_gas < 4.0);
+        let gas_price = result.to_legacy();
+        assert_eq!(result.fee_model(), FeeModel::Legacy);
+        assert!(gas_price > 3_000_000_000u64.into() && gas_price < 4_000_000_000u64.into());
     }
 
     #[test]
@@ -202,15 +203,41 @@ mod tests {
         let gasnow = GasNowGasStation::new(TestTransport::default());
         let now = Instant::now();
 
-        assert!(gasnow
-            .gas_price_with_cache(now, || ready(Err(anyhow!(""))))
-            .wait()
-            .is_err());
+        assert!(matches!(
+            gasnow
+                .gas_price_with_cache(now, || ready(Err(Error::NoData)))
+                .wait(),
+            Err(Error::NoData)
+        ));
         // panic_future isn't called
-        assert!(gasnow
-            .gas_price_with_cache(now, panic_future)
+        assert!(matches!(
+            gasnow.gas_price_with_cache(now, panic_future).wait(),
+            Err(Error::NoData)
+        ));
+    }
+
+    #[test]
+    fn cache_expires_after_configured_rate_limit() {
+        let gasnow =
+            GasNowGasStation::new(TestTransport::default()).with_rate_limit(Duration::from_secs(2));
+        let now = Instant::now();
+        let response = |code| Response {
+            code,
+            ..Default::default()
+        };
+
+        gasnow
+            .gas_price_with_cache(now, || ready(Ok(response(0))))
             .wait()
-            .is_err());
+            .unwrap();
+        let now = now + Duration::from_secs(2);
+        assert_eq!(
+            gasnow
+                .gas_price_with_cache(now, || ready(Ok(response(1))))
+                .wait()
+                .unwrap(),
+            response(1)
+        );
     }
 
     #[test]
@@ -219,7 +246,7 @@ mod tests {
         let now = Instant::now();
         *gasnow.last_response.lock().now_or_never().unwrap() = Some(CachedResponse {
             time: now + Duration::from_secs(1),
-            data: None,
+            data: Err(Error::NoData),
         });
         gasnow
             .gas_price_with_cache(now, panic_future)
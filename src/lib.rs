@@ -1,24 +1,54 @@
 //! # Features
-//! `web3_`: Implements `GasPriceEstimating` for `Web3`.
+//! `web3_`: Implements `GasPriceEstimating` for `Web3` and provides `EthNode`. Together with
+//! `tokio_` provides `NativeGasEstimator`.
+//! `reqwest_`: Provides `ReqwestTransport`, a `Transport` backed by `reqwest`.
+//! `tokio_`: Provides `BackgroundRefresh`, `BlockNative` and `RetryingTransport`.
+//! `metrics`: Records Prometheus metrics, see the `metrics` module.
 
+pub mod aggregating;
+#[cfg(feature = "tokio_")]
+pub mod background;
+pub mod base_fee;
 #[cfg(feature = "tokio_")]
 pub mod blocknative;
+pub mod error;
+pub mod estimate;
 #[cfg(feature = "web3_")]
 pub mod eth_node;
 pub mod gas_price;
 pub mod gasnow;
 mod linear_interpolation;
-#[cfg(feature = "web3_")]
+pub mod metrics;
+#[cfg(all(feature = "web3_", feature = "tokio_"))]
 pub mod nativegasestimator;
 pub mod priority;
+pub mod request;
+#[cfg(feature = "reqwest_")]
+pub mod reqwest_transport;
+#[cfg(feature = "tokio_")]
+pub mod retry;
+pub mod sanity;
 
+pub use aggregating::AggregatingGasPriceEstimating;
+#[cfg(feature = "tokio_")]
+pub use background::BackgroundRefresh;
 #[cfg(feature = "tokio_")]
 pub use blocknative::BlockNative;
-pub use gas_price::GasPrice1559;
+pub use error::{Error, Result};
+pub use estimate::Estimate;
+#[cfg(feature = "web3_")]
+pub use eth_node::EthNode;
+pub use gas_price::{FeeModel, GasPrice, GasPrice1559, ReplacementRules};
 pub use gasnow::GasNowGasStation;
+pub use primitive_types::U256;
 pub use priority::PriorityGasPriceEstimating;
+pub use request::EstimationRequest;
+#[cfg(feature = "reqwest_")]
+pub use reqwest_transport::ReqwestTransport;
+#[cfg(feature = "tokio_")]
+pub use retry::RetryingTransport;
+pub use sanity::SanityCheckedEstimator;
 
-use anyhow::Result;
 use serde::de::DeserializeOwned;
 use std::time::Duration;
 
@@ -29,16 +59,32 @@ pub const DEFAULT_TIME_LIMIT: Duration = Duration::from_secs(30);
 #[async_trait::async_trait]
 pub trait GasPriceEstimating: Send + Sync {
     /// Estimate the gas price for a transaction to be mined "quickly".
-    async fn estimate(&self) -> Result<GasPrice1559> {
+    async fn estimate(&self) -> Result<GasPrice> {
         self.estimate_with_limits(DEFAULT_GAS_LIMIT, DEFAULT_TIME_LIMIT)
             .await
     }
     /// Estimate the gas price for a transaction that uses <gas> to be mined within <time_limit>.
-    async fn estimate_with_limits(
-        &self,
-        gas_limit: f64,
-        time_limit: Duration,
-    ) -> Result<GasPrice1559>;
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice>;
+    /// Estimate the gas price for a transaction described by <request>.
+    ///
+    /// The default implementation honours `gas_limit` and `time_limit` through
+    /// `estimate_with_limits` and the estimator independent fields through
+    /// `EstimationRequest::apply`. Estimators that can make use of the other fields override it.
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        let gas_price = self
+            .estimate_with_limits(request.gas_limit, request.time_limit)
+            .await?;
+        request.apply(gas_price)
+    }
+    /// Estimate the gas price for a transaction described by <request> together with metadata
+    /// about the estimate.
+    ///
+    /// The default implementation uses the type name as source and leaves the rest of the metadata
+    /// unknown.
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        let gas_price = self.estimate_with_request(request).await?;
+        Ok(Estimate::new(gas_price, std::any::type_name::<Self>()))
+    }
 }
 
 #[async_trait::async_trait]
@@ -54,6 +100,11 @@ pub trait Transport: Send + Sync {
 mod tests {
     use super::*;
     use std::future::Future;
+    #[cfg(feature = "web3_")]
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::{Arc, Mutex},
+    };
 
     #[derive(Default)]
     pub struct TestTransport {}
@@ -69,14 +120,94 @@ mod tests {
                 .get(url)
                 .headers(header)
                 .send()
-                .await?
+                .await
+                .map_err(Error::transport)?
                 .text()
-                .await?;
+                .await
+                .map_err(Error::transport)?;
 
             Ok(serde_json::from_str(&json)?)
         }
     }
 
+    #[cfg(feature = "web3_")]
+    type Params = Vec<serde_json::Value>;
+
+    /// Web3 transport answering every call with the next response queued for its method. The last
+    /// response of a method is repeated once the others are used up.
+    #[cfg(feature = "web3_")]
+    #[derive(Clone, Debug, Default)]
+    pub struct Web3TestTransport {
+        responses: Arc<Mutex<HashMap<String, VecDeque<serde_json::Value>>>>,
+        // methods and params of all calls
+        calls: Arc<Mutex<Vec<(String, Params)>>>,
+    }
+
+    #[cfg(feature = "web3_")]
+    impl Web3TestTransport {
+        pub fn add_response(&self, method: &str, response: serde_json::Value) {
+            self.responses
+                .lock()
+                .unwrap()
+                .entry(method.to_string())
+                .or_default()
+                .push_back(response);
+        }
+
+        #[cfg_attr(not(feature = "tokio_"), allow(dead_code))]
+        pub fn calls(&self, method: &str) -> usize {
+            self.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(m, _)| m == method)
+                .count()
+        }
+
+        #[cfg_attr(not(feature = "tokio_"), allow(dead_code))]
+        pub fn params(&self, method: &str) -> Vec<Params> {
+            self.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(m, _)| m == method)
+                .map(|(_, params)| params.clone())
+                .collect()
+        }
+    }
+
+    #[cfg(feature = "web3_")]
+    impl web3::Transport for Web3TestTransport {
+        type Out = futures::future::Ready<web3::Result<serde_json::Value>>;
+
+        fn prepare(
+            &self,
+            method: &str,
+            params: Vec<serde_json::Value>,
+        ) -> (web3::RequestId, jsonrpc_core::Call) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push((method.to_string(), params.clone()));
+            (0, web3::helpers::build_request(0, method, params))
+        }
+
+        fn send(&self, _: web3::RequestId, request: jsonrpc_core::Call) -> Self::Out {
+            let jsonrpc_core::Call::MethodCall(call) = request else {
+                return futures::future::ready(Err(web3::Error::Unreachable));
+            };
+            let mut responses = self.responses.lock().unwrap();
+            let response = responses.get_mut(&call.method).and_then(|queue| {
+                if queue.len() > 1 {
+                    queue.pop_front()
+                } else {
+                    queue.front().cloned()
+                }
+            });
+            futures::future::ready(response.ok_or(web3::Error::Unreachable))
+        }
+    }
+
     pub trait FutureWaitExt: Future + Sized {
         fn wait(self) -> Self::Output {
             futures::executor::block_on(self)
@@ -1,24 +1,62 @@
-use anyhow::{anyhow, Error};
+use crate::gas_price::mul_f64_floor;
+use crate::Error;
+use primitive_types::U256;
 use std::convert::TryFrom;
 
+/// A y value that can be linearly interpolated.
+pub trait Value: Copy {
+    fn is_finite(&self) -> bool;
+
+    /// Interpolate between `(x0, y0)` and `(x1, y1)` at `x`, where `x0 <= x < x1`.
+    fn interpolate(x: f64, p0: (f64, Self), p1: (f64, Self)) -> Self;
+}
+
+impl Value for f64 {
+    fn is_finite(&self) -> bool {
+        f64::is_finite(*self)
+    }
+
+    fn interpolate(x: f64, (x0, y0): (f64, Self), (x1, y1): (f64, Self)) -> Self {
+        // https://en.wikipedia.org/wiki/Linear_interpolation#Linear_interpolation_between_two_known_points
+        y0 + (x - x0) * ((y1 - y0) / (x1 - x0))
+    }
+}
+
+// Integer amounts are interpolated exactly apart from the fractional position between the two
+// points, the result is rounded towards y0.
+impl Value for U256 {
+    fn is_finite(&self) -> bool {
+        true
+    }
+
+    fn interpolate(x: f64, (x0, y0): (f64, Self), (x1, y1): (f64, Self)) -> Self {
+        let fraction = (x - x0) / (x1 - x0);
+        if y1 >= y0 {
+            y0 + mul_f64_floor(y1 - y0, fraction)
+        } else {
+            y0 - mul_f64_floor(y0 - y1, fraction)
+        }
+    }
+}
+
 /// Not empty and contains unique x values sorted in ascending order.
 #[derive(Copy, Clone, Debug)]
-pub struct Points<'a>(&'a [(f64, f64)]);
+pub struct Points<'a, Y = f64>(&'a [(f64, Y)]);
 
-impl<'a> TryFrom<&'a [(f64, f64)]> for Points<'a> {
+impl<'a, Y: Value> TryFrom<&'a [(f64, Y)]> for Points<'a, Y> {
     type Error = Error;
 
-    fn try_from(points: &'a [(f64, f64)]) -> Result<Self, Self::Error> {
+    fn try_from(points: &'a [(f64, Y)]) -> Result<Self, Self::Error> {
         let is_finite = points
             .iter()
             .all(|point| point.0.is_finite() && point.1.is_finite());
         let is_sorted_and_unique = points.windows(2).all(|window| window[0].0 < window[1].0);
         if points.is_empty() {
-            Err(anyhow!("points is empty"))
+            Err(Error::validation("points is empty"))
         } else if !is_finite {
-            Err(anyhow!("points contains non finite value"))
+            Err(Error::validation("points contains non finite value"))
         } else if !is_sorted_and_unique {
-            Err(anyhow!("points is not sorted an unique"))
+            Err(Error::validation("points is not sorted an unique"))
         } else {
             Ok(Self(points))
         }
@@ -28,7 +66,7 @@ impl<'a> TryFrom<&'a [(f64, f64)]> for Points<'a> {
 /// Linearly interpolate `value` between `points`.
 ///
 /// If `value` is smaller than the first point or larger than the last it is clamped.
-pub fn interpolate(value: f64, points: Points) -> f64 {
+pub fn interpolate<Y: Value>(value: f64, points: Points<Y>) -> Y {
     let points = points.0;
     if value < points[0].0 {
         points[0].1
@@ -36,9 +74,7 @@ pub fn interpolate(value: f64, points: Points) -> f64 {
         .windows(2)
         .find(|window| value >= window[0].0 && value < window[1].0)
     {
-        // https://en.wikipedia.org/wiki/Linear_interpolation#Linear_interpolation_between_two_known_points
-        let (x, x0, y0, x1, y1) = (value, window[0].0, window[0].1, window[1].0, window[1].1);
-        y0 + (x - x0) * ((y1 - y0) / (x1 - x0))
+        Y::interpolate(value, window[0], window[1])
     } else {
         points.last().unwrap().1
     }
@@ -83,9 +119,25 @@ mod tests {
         assert_approx_eq!(interpolate(3.0, points), 1.0);
     }
 
+    #[test]
+    fn interpolate_integers() {
+        let points = [
+            (0.0, U256::from(10)),
+            (1.0, U256::from(0)),
+            (3.0, U256::from(20)),
+        ];
+        let points = Points::try_from(points.as_ref()).unwrap();
+        assert_eq!(interpolate(-1.0, points), 10.into());
+        assert_eq!(interpolate(0.5, points), 5.into());
+        assert_eq!(interpolate(0.75, points), 3.into());
+        assert_eq!(interpolate(1.0, points), 0.into());
+        assert_eq!(interpolate(2.0, points), 10.into());
+        assert_eq!(interpolate(4.0, points), 20.into());
+    }
+
     #[test]
     fn points_must_not_be_empty() {
-        assert!(Points::try_from([].as_ref()).is_err());
+        assert!(Points::<f64>::try_from([].as_ref()).is_err());
     }
 
     #[test]
@@ -0,0 +1,352 @@
+//! Prometheus metrics, registered in the default registry when the `metrics` feature is enabled.
+//! If a name is already taken in the registry the metric is recorded but not exported and a
+//! warning is logged.
+//!
+//! All metrics are labelled by the name of the estimator:
+//! - `gas_estimation_estimate_seconds`: latency of estimates
+//! - `gas_estimation_estimates_total`: estimates by `result` (`success` or `failure`)
+//! - `gas_estimation_stale_cache_hits_total`: estimates that failed because of a stale cache
+//! - `gas_estimation_refresh_seconds`: duration of background refreshes
+//! - `gas_estimation_fee_wei`: fee `field`s of the latest successful estimate
+//!
+//! The functions can be used to record custom estimators. Without the feature they do nothing.
+
+use crate::GasPrice;
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+pub use metered::MeteredEstimator;
+
+#[cfg(feature = "metrics")]
+mod metered {
+    use crate::{Estimate, EstimationRequest, GasPrice, GasPriceEstimating, Result};
+    use std::time::{Duration, Instant};
+
+    /// Records the latency, result and fees of the estimates of the inner estimator.
+    ///
+    /// Estimators in a `PriorityGasPriceEstimating` or an `AggregatingGasPriceEstimating` are
+    /// already recorded under their name.
+    pub struct MeteredEstimator<E> {
+        name: String,
+        inner: E,
+    }
+
+    impl<E: GasPriceEstimating> MeteredEstimator<E> {
+        pub fn new(name: impl Into<String>, inner: E) -> Self {
+            Self {
+                name: name.into(),
+                inner,
+            }
+        }
+
+        fn record<R>(
+            &self,
+            start: Instant,
+            result: &Result<R>,
+            gas_price: impl Fn(&R) -> GasPrice,
+        ) {
+            super::estimate(
+                &self.name,
+                start.elapsed(),
+                result.as_ref().ok().map(gas_price).as_ref(),
+            );
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl<E: GasPriceEstimating> GasPriceEstimating for MeteredEstimator<E> {
+        async fn estimate_with_limits(
+            &self,
+            gas_limit: f64,
+            time_limit: Duration,
+        ) -> Result<GasPrice> {
+            let start = Instant::now();
+            let result = self.inner.estimate_with_limits(gas_limit, time_limit).await;
+            self.record(start, &result, |gas_price| *gas_price);
+            result
+        }
+
+        async fn estimate(&self) -> Result<GasPrice> {
+            let start = Instant::now();
+            let result = self.inner.estimate().await;
+            self.record(start, &result, |gas_price| *gas_price);
+            result
+        }
+
+        async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+            let start = Instant::now();
+            let result = self.inner.estimate_with_request(request).await;
+            self.record(start, &result, |gas_price| *gas_price);
+            result
+        }
+
+        async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+            let start = Instant::now();
+            let result = self.inner.estimate_detailed(request).await;
+            self.record(start, &result, |estimate| estimate.gas_price);
+            result
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+struct Metrics {
+    latency: prometheus::HistogramVec,
+    estimates: prometheus::IntCounterVec,
+    stale_cache_hits: prometheus::IntCounterVec,
+    refresh: prometheus::HistogramVec,
+    fees: prometheus::GaugeVec,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn get() -> &'static Self {
+        static METRICS: std::sync::OnceLock<Metrics> = std::sync::OnceLock::new();
+        METRICS.get_or_init(|| Metrics::new(prometheus::default_registry()))
+    }
+
+    // Metrics whose names are already taken in the registry, for example by another version of
+    // this crate, are recorded but not exported instead of failing.
+    fn new(registry: &prometheus::Registry) -> Self {
+        use prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts};
+        fn register<C: prometheus::core::Collector + Clone + 'static>(
+            registry: &prometheus::Registry,
+            collector: C,
+        ) -> C {
+            if let Err(err) = registry.register(Box::new(collector.clone())) {
+                tracing::warn!(?err, "failed to register gas estimation metric");
+            }
+            collector
+        }
+        Metrics {
+            latency: register(
+                registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "gas_estimation_estimate_seconds",
+                        "Latency of gas price estimates",
+                    ),
+                    &["estimator"],
+                )
+                .unwrap(),
+            ),
+            estimates: register(
+                registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "gas_estimation_estimates_total",
+                        "Number of gas price estimates by result",
+                    ),
+                    &["estimator", "result"],
+                )
+                .unwrap(),
+            ),
+            stale_cache_hits: register(
+                registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "gas_estimation_stale_cache_hits_total",
+                        "Number of estimates that failed because the cached response was stale",
+                    ),
+                    &["estimator"],
+                )
+                .unwrap(),
+            ),
+            refresh: register(
+                registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "gas_estimation_refresh_seconds",
+                        "Duration of background refreshes of cached responses",
+                    ),
+                    &["estimator"],
+                )
+                .unwrap(),
+            ),
+            fees: register(
+                registry,
+                GaugeVec::new(
+                    Opts::new(
+                        "gas_estimation_fee_wei",
+                        "Fee fields of the latest successful gas price estimate",
+                    ),
+                    &["estimator", "field"],
+                )
+                .unwrap(),
+            ),
+        }
+    }
+}
+
+/// Records an estimate, `gas_price` is None if it failed.
+#[cfg(feature = "metrics")]
+pub fn estimate(estimator: &str, elapsed: Duration, gas_price: Option<&GasPrice>) {
+    let metrics = Metrics::get();
+    metrics
+        .latency
+        .with_label_values(&[estimator])
+        .observe(elapsed.as_secs_f64());
+    let result = if gas_price.is_some() {
+        "success"
+    } else {
+        "failure"
+    };
+    metrics
+        .estimates
+        .with_label_values(&[estimator, result])
+        .inc();
+    if let Some(gas_price) = gas_price {
+        let fee = gas_price.to_eip1559();
+        let fields = [
+            ("base_fee_per_gas", Some(fee.base_fee_per_gas)),
+            ("max_fee_per_gas", Some(fee.max_fee_per_gas)),
+            (
+                "max_priority_fee_per_gas",
+                Some(fee.max_priority_fee_per_gas),
+            ),
+            ("max_fee_per_blob_gas", gas_price.max_fee_per_blob_gas()),
+        ];
+        for (field, value) in fields {
+            if let Some(value) = value {
+                metrics
+                    .fees
+                    .with_label_values(&[estimator, field])
+                    .set(value.to_f64_lossy());
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn estimate(_: &str, _: Duration, _: Option<&GasPrice>) {}
+
+/// Records an estimate that failed because the cached response was stale.
+#[cfg(feature = "metrics")]
+pub fn stale_cache_hit(estimator: &str) {
+    Metrics::get()
+        .stale_cache_hits
+        .with_label_values(&[estimator])
+        .inc();
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn stale_cache_hit(_: &str) {}
+
+/// Records the duration of a background refresh.
+#[cfg(feature = "metrics")]
+pub fn refresh(estimator: &str, elapsed: Duration) {
+    Metrics::get()
+        .refresh
+        .with_label_values(&[estimator])
+        .observe(elapsed.as_secs_f64());
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn refresh(_: &str, _: Duration) {}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::GasPrice1559;
+
+    #[test]
+    fn records_estimates() {
+        let gas_price = GasPrice::from(GasPrice1559 {
+            base_fee_per_gas: 1.into(),
+            max_fee_per_gas: 3.into(),
+            max_priority_fee_per_gas: 2.into(),
+        })
+        .with_blob_fee(4.into());
+        estimate("test", Duration::from_millis(5), Some(&gas_price));
+        estimate("test", Duration::from_millis(5), None);
+        stale_cache_hit("test");
+        refresh("test", Duration::from_millis(5));
+
+        let metrics = Metrics::get();
+        let count = |result: &str| metrics.estimates.with_label_values(&["test", result]).get();
+        assert_eq!(count("success"), 1);
+        assert_eq!(count("failure"), 1);
+        assert_eq!(
+            metrics
+                .fees
+                .with_label_values(&["test", "max_fee_per_blob_gas"])
+                .get(),
+            4.0
+        );
+        assert_eq!(
+            metrics.stale_cache_hits.with_label_values(&["test"]).get(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .latency
+                .with_label_values(&["test"])
+                .get_sample_count(),
+            2
+        );
+    }
+
+    #[test]
+    fn records_aggregated_estimators() {
+        use crate::{
+            aggregating::{self, AggregatingGasPriceEstimating},
+            priority::NamedEstimator,
+            Error, GasPriceEstimating, MockGasPriceEstimating,
+        };
+        use futures::FutureExt;
+
+        let estimator = |result: fn() -> crate::Result<GasPrice>| {
+            let mut estimator = MockGasPriceEstimating::new();
+            estimator.expect_estimate().returning(result);
+            Box::new(estimator)
+        };
+        let aggregating = AggregatingGasPriceEstimating::named(
+            vec![
+                NamedEstimator::new(
+                    "aggregated_node",
+                    estimator(|| {
+                        Ok(GasPrice::Legacy {
+                            gas_price: 1.into(),
+                        })
+                    }),
+                ),
+                NamedEstimator::new("aggregated_api", estimator(|| Err(Error::NoData))),
+            ],
+            aggregating::Params {
+                quorum: 1,
+                ..Default::default()
+            },
+        );
+        aggregating.estimate().now_or_never().unwrap().unwrap();
+
+        let metrics = Metrics::get();
+        let count = |estimator: &str, result: &str| {
+            metrics
+                .estimates
+                .with_label_values(&[estimator, result])
+                .get()
+        };
+        assert_eq!(count("aggregated_node", "success"), 1);
+        assert_eq!(count("aggregated_api", "failure"), 1);
+    }
+
+    #[test]
+    fn tolerates_registered_names() {
+        let registry = prometheus::Registry::new();
+        let taken = prometheus::IntCounter::new("gas_estimation_estimates_total", "taken").unwrap();
+        registry.register(Box::new(taken)).unwrap();
+
+        let metrics = Metrics::new(&registry);
+        let estimates = metrics.estimates.with_label_values(&["test", "success"]);
+        estimates.inc();
+        assert_eq!(estimates.get(), 1);
+        // the other metrics are registered
+        metrics.latency.with_label_values(&["test"]).observe(1.0);
+        assert!(registry
+            .gather()
+            .iter()
+            .any(|family| family.get_name() == "gas_estimation_estimate_seconds"));
+        // a second set of metrics in the same registry
+        Metrics::new(&registry);
+    }
+}
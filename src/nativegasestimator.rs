@@ -1,26 +1,37 @@
 //! Native gas price estimator based on the https://github.com/zsfelfoldi/feehistory/blob/main/docs/feeOracle.md
 
-use super::{linear_interpolation, GasPrice1559, GasPriceEstimating};
-use anyhow::{anyhow, ensure, Context, Result};
+use super::{
+    background::{self, BackgroundRefresh, Interval, Refresh, Trigger},
+    base_fee,
+    gas_price::mul_f64_floor,
+    linear_interpolation, Estimate, EstimationRequest, GasPrice, GasPrice1559, GasPriceEstimating,
+};
+use crate::{Error, Result};
+use futures::{stream::Fuse, FutureExt, Stream, StreamExt};
+use serde::Deserialize;
 use std::{
+    collections::VecDeque,
     convert::TryInto,
     f64::consts::{E, PI},
     fmt::Debug,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    pin::Pin,
+    time::Duration,
 };
-use tokio::task::{self, JoinHandle};
 use web3::{
-    types::{BlockNumber, U256},
-    Transport,
+    api::SubscriptionStream,
+    helpers::{self, CallFuture},
+    types::{Block, BlockHeader, BlockNumber, H256, U256, U64},
+    DuplexTransport, Transport,
 };
 
-const CACHED_RESPONSE_VALIDITY: Duration = Duration::from_secs(60);
-
-//rate limit of ethereum L1 nodes
-const RATE_LIMIT: Duration = Duration::from_secs(5);
+const NAME: &str = "native";
 
 /// Parameters for Native gas price estimator algorithm
+///
+/// The max fee per gas covers the base fee even if every block until the deadline is full, for up
+/// to `max_fee_blocks` blocks. Longer deadlines can become underpriced before the deadline if the
+/// base fee keeps rising. The other fees of deadlines beyond `max_time_factor` blocks are those for
+/// `max_time_factor` blocks.
 #[derive(Debug, Clone)]
 pub struct Params {
     // sampled percentile range of exponentially weighted baseFee history
@@ -38,13 +49,24 @@ pub struct Params {
     // extra priority fee offered in case of expected baseFee rise
     pub extra_priority_fee_ratio: f64,
     // a little extra to add to have a non-rounded value
-    pub extra_priority_fee_boost: f64,
+    pub extra_priority_fee_boost: U256,
     // priority fee offered when there are no recent transactions
-    pub fallback_priority_fee: f64,
-    // a coefficient to multiply base_fee_per_gas with, in order to increase chances of transaction inclusion
-    pub bump_cap_coefficient: f64,
+    pub fallback_priority_fee: U256,
     // number of blocks to consider for fee history calculation
     pub fee_history_blocks: u64,
+    // number of recent blocks whose average gas used ratio is taken as the current demand for block space
+    pub block_space_blocks: usize,
+    // time between blocks used to convert time limits into a number of blocks, observed from the
+    // block timestamps of the fee history if not set
+    pub block_time: Option<Duration>,
+    // base fee update rule of the chain, used for the base fee of the pending block and the max fee
+    // per gas
+    pub base_fee: base_fee::Params,
+    // largest number of blocks of worst-case base fee growth covered by the max fee per gas, lower
+    // values keep the max fee of long deadlines reasonable at the cost of the deadline guarantee
+    // (12 blocks are a factor of about 4.1 on mainnet, the default of 127 blocks covers
+    // `max_time_factor`)
+    pub max_fee_blocks: u64,
 }
 
 impl Default for Params {
@@ -57,10 +79,13 @@ impl Default for Params {
             max_block_percentile: 60.0,
             max_time_factor: 128.0,
             extra_priority_fee_ratio: 0.25,
-            extra_priority_fee_boost: 1559.0,
-            fallback_priority_fee: 2e9,
-            bump_cap_coefficient: 2.0,
+            extra_priority_fee_boost: 1559.into(),
+            fallback_priority_fee: 2_000_000_000u64.into(),
             fee_history_blocks: 300,
+            block_space_blocks: 10,
+            block_time: None,
+            base_fee: Default::default(),
+            max_fee_blocks: 127,
         }
     }
 }
@@ -68,34 +93,74 @@ impl Default for Params {
 /// can handle, we need to have a cached value that will be returned instead of error.
 #[derive(Debug, Clone)]
 pub struct CachedResponse {
-    // The time at which the cache is last time updated.
-    time: Instant,
-    // List of gas price estimates, coupled with time_limit
-    data: Vec<(f64, GasPrice1559)>,
+    // List of gas price estimates, coupled with time_limit. Estimates include the max fee per blob
+    // gas if the node reports blob base fees.
+    data: Vec<(f64, GasPrice)>,
+    // Recent demand for block space, missing if no rewards could be collected.
+    block_space: Option<BlockSpace>,
+    // Time between blocks.
+    block_time: Duration,
+    // Number of the latest block the estimates are based on.
+    block_number: u64,
+    // Blob base fee of the pending block, zero without blob base fees.
+    pending_blob_base_fee: U256,
+    // Base fee rule of the chain and `Params::max_fee_blocks`, to cover the worst-case base fee
+    // until the deadline of a request. The max fees of `data` only cover the predicted base fee.
+    base_fee: base_fee::Params,
+    max_fee_blocks: u64,
 }
 
-impl Default for CachedResponse {
-    fn default() -> Self {
-        Self {
-            time: Instant::now(),
-            data: Default::default(),
+/// Recent demand for block space. Used to price transactions by the share of the block they use.
+#[derive(Debug, Clone)]
+struct BlockSpace {
+    // gas limit of the latest block
+    block_gas_limit: f64,
+    // average gas used ratio of recent blocks
+    gas_used_ratio: f64,
+    // gas weighted priority fee distribution, for each of the `reward_percentiles` the median
+    // reward of the sampled blocks
+    rewards: Vec<U256>,
+}
+
+impl BlockSpace {
+    // Priority fee needed for a transaction using `gas_limit` to get included given the recent
+    // demand. A transaction taking up `share` of the block leaves room for `1 - share` of the other
+    // transactions. If the demand exceeds that, the cheapest `(demand + share - 1) / demand` of the
+    // other transactions' gas gets displaced so the transaction has to pay the priority fee at that
+    // gas weighted percentile.
+    fn priority_fee(&self, gas_limit: f64) -> Option<U256> {
+        if self.rewards.is_empty() || self.block_gas_limit <= 0.0 || self.gas_used_ratio <= 0.0 {
+            return None;
+        }
+        let share = (gas_limit / self.block_gas_limit).clamp(0.0, 1.0);
+        let displaced = (self.gas_used_ratio + share - 1.0) / self.gas_used_ratio;
+        if displaced.is_nan() || displaced <= 0.0 {
+            return None;
         }
+        let points = reward_percentiles()
+            .into_iter()
+            .zip(self.rewards.iter().copied())
+            .collect::<Vec<_>>();
+        Some(linear_interpolation::interpolate(
+            displaced.min(1.0) * 100.0,
+            points.as_slice().try_into().ok()?,
+        ))
     }
 }
 
+/// Produces `GasPrice::Eip4844` estimates on chains that report blob base fees in `eth_feeHistory`
+/// and `GasPrice::Eip1559` estimates otherwise.
+///
+/// The fees are computed from the fee history and refreshed in the background. The max fee per gas
+/// keeps the transaction valid until the deadline of the request even if all blocks are full, up
+/// to `Params::max_fee_blocks` blocks.
 pub struct NativeGasEstimator {
-    cached_response: Arc<Mutex<CachedResponse>>,
-    handle: JoinHandle<()>,
-}
-
-impl Drop for NativeGasEstimator {
-    fn drop(&mut self) {
-        self.handle.abort();
-        self.cached_response = Default::default();
-    }
+    cache: BackgroundRefresh<Box<dyn Refresh<Value = CachedResponse>>>,
 }
 
 impl NativeGasEstimator {
+    /// Refreshes according to `background::Config::mainnet`. Fails if the initial calculation of
+    /// the fees fails.
     pub async fn new<T: Transport + Send + Sync + 'static>(
         transport: T,
         params: Option<Params>,
@@ -103,71 +168,428 @@ impl NativeGasEstimator {
     where
         <T as Transport>::Out: std::marker::Send,
     {
-        let cached_response: Arc<Mutex<CachedResponse>> = Default::default();
-        let cached_response_clone = cached_response.clone();
-        let params = params.unwrap_or_default();
-
-        //do one calculation to initially populate cache before any request for gas price estimation is received from our users
-        match suggest_fee(transport.clone(), &params).await {
-            Ok(fees) => {
-                // bump cap to be the ~ 2 x base_fee_per_gas (similar as BlockNative does) or ~ 2 x max_fee_per_gas, whichever is higher
-                let fees = fees
-                    .into_iter()
-                    .map(|(time_limit, mut gas_price)| {
-                        // TODO: Double check with Dusan whether this makes sense. I haven't changed
-                        // the logic but this feels weird. (same in `handle` below)
-                        gas_price.max_fee_per_gas =
-                            gas_price.max_fee_per_gas.max(gas_price.base_fee_per_gas)
-                                * params.bump_cap_coefficient;
-                        (time_limit, gas_price)
-                    })
-                    .collect();
-
-                *cached_response_clone.lock().unwrap() = CachedResponse {
-                    time: Instant::now(),
-                    data: fees,
-                };
+        Self::with_config(transport, params, background::Config::mainnet()).await
+    }
+
+    /// Refreshes according to `config`, see the presets of `background::Config` for chains with
+    /// faster blocks.
+    pub async fn with_config<T: Transport + Send + Sync + 'static>(
+        transport: T,
+        params: Option<Params>,
+        config: background::Config,
+    ) -> Result<Self>
+    where
+        <T as Transport>::Out: std::marker::Send,
+    {
+        let interval = Interval(config.interval);
+        Self::with_trigger(transport, params, config, interval).await
+    }
+
+    /// Recomputes the fees whenever `trigger` fires, for example once per block with `NewHeads`
+    /// or `BlockPolling`. Failed computations are retried after the retry interval of `config`.
+    pub async fn with_trigger<T: Transport + Send + Sync + 'static>(
+        transport: T,
+        params: Option<Params>,
+        config: background::Config,
+        trigger: impl Trigger,
+    ) -> Result<Self>
+    where
+        <T as Transport>::Out: std::marker::Send,
+    {
+        let fees: Box<dyn Refresh<Value = CachedResponse>> = Box::new(Fees {
+            transport,
+            params: params.unwrap_or_default(),
+            history: Default::default(),
+        });
+        let cache = BackgroundRefresh::with_trigger(NAME, fees, config, trigger).await?;
+        Ok(Self { cache })
+    }
+}
+
+/// Fires once for every new block announced by an `eth_subscribe("newHeads")` subscription.
+///
+/// Blocks announced while the refresh is running cause a single refresh once it is done. When the
+/// subscription ends it subscribes again and fires, since blocks may have been missed.
+pub struct NewHeads<T: DuplexTransport> {
+    web3: web3::Web3<T>,
+    stream: Fuse<Pin<Box<SubscriptionStream<T, BlockHeader>>>>,
+    // hash of the last announced block
+    last: Option<H256>,
+}
+
+impl<T: DuplexTransport> NewHeads<T> {
+    pub async fn new(transport: T) -> Result<Self> {
+        let web3 = web3::Web3::new(transport);
+        let stream = web3.eth_subscribe().subscribe_new_heads().await?;
+        Ok(Self {
+            web3,
+            stream: Box::pin(stream).fuse(),
+            last: None,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<T> Trigger for NewHeads<T>
+where
+    T: DuplexTransport + Send + Sync + 'static,
+    <T as Transport>::Out: Send,
+    T::NotificationStream: Send,
+{
+    async fn next(&mut self) -> Result<()> {
+        match next_head(&mut self.stream, &mut self.last).await {
+            Some(result) => result,
+            None => {
+                tracing::debug!("newHeads subscription ended, subscribing again");
+                let stream = self.web3.eth_subscribe().subscribe_new_heads().await?;
+                self.stream = Box::pin(stream).fuse();
+                Ok(())
             }
-            Err(err) => {
-                tracing::warn!(?err, "failed to calculate initial fees");
-                return Err(anyhow!("failed to calculate initial fees"));
+        }
+    }
+}
+
+// Waits for a block other than `last`, since nodes can announce the same block more than once, and
+// skips the blocks already announced after it so that they don't cause a refresh each. None if
+// the stream ended.
+async fn next_head<S>(stream: &mut Fuse<S>, last: &mut Option<H256>) -> Option<Result<()>>
+where
+    S: Stream<Item = web3::Result<BlockHeader>> + Unpin,
+{
+    loop {
+        let header = match stream.next().await? {
+            Ok(header) => header,
+            Err(err) => return Some(Err(err.into())),
+        };
+        if header.hash != *last {
+            *last = header.hash;
+            break;
+        }
+    }
+    while let Some(Some(header)) = stream.next().now_or_never() {
+        if let Ok(header) = header {
+            *last = header.hash;
+        }
+    }
+    Some(Ok(()))
+}
+
+/// Fires once for every new block observed by polling `eth_blockNumber`.
+///
+/// The first poll only records the current block.
+pub struct BlockPolling<T: Transport> {
+    web3: web3::Web3<T>,
+    // time between two polls
+    poll_interval: Duration,
+    // latest observed block number
+    last: Option<U64>,
+}
+
+impl<T: Transport> BlockPolling<T> {
+    pub fn new(transport: T, poll_interval: Duration) -> Self {
+        Self {
+            web3: web3::Web3::new(transport),
+            poll_interval,
+            last: None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T> Trigger for BlockPolling<T>
+where
+    T: Transport + Send + Sync + 'static,
+    <T as Transport>::Out: Send,
+{
+    async fn next(&mut self) -> Result<()> {
+        loop {
+            let block_number = self.web3.eth().block_number().await?;
+            if is_new_block(&mut self.last, block_number) {
+                return Ok(());
             }
+            tokio::time::sleep(self.poll_interval).await;
         }
+    }
+}
 
-        //spawn task for updating the cached response every RATE_LIMIT seconds
-        let handle = task::spawn(async move {
-            loop {
-                tokio::time::sleep(RATE_LIMIT).await;
-                let start = Instant::now();
-                let fee = suggest_fee(transport.clone(), &params).await;
-                tracing::debug!("suggested fees in {} s", start.elapsed().as_secs_f32());
-                match fee {
-                    Ok(fees) => {
-                        // bump cap to be the ~ 2 x base_fee_per_gas (similar as BlockNative does) or ~ 2 x max_fee_per_gas, whichever is higher
-                        let fees = fees
-                            .into_iter()
-                            .map(|(time_limit, mut gas_price)| {
-                                gas_price.max_fee_per_gas =
-                                    gas_price.max_fee_per_gas.max(gas_price.base_fee_per_gas)
-                                        * params.bump_cap_coefficient;
-                                (time_limit, gas_price)
-                            })
-                            .collect();
-
-                        *cached_response_clone.lock().unwrap() = CachedResponse {
-                            time: Instant::now(),
-                            data: fees,
-                        };
-                    }
-                    Err(err) => tracing::warn!(?err, "failed to calculate fees"),
+// Whether the polled block number is newer than the latest observed one, which it replaces. Block
+// numbers that go backwards are ignored until the chain has passed the latest one again.
+fn is_new_block(last: &mut Option<U64>, block_number: U64) -> bool {
+    match *last {
+        Some(last) if block_number <= last => false,
+        previous => {
+            *last = Some(block_number);
+            previous.is_some()
+        }
+    }
+}
+
+struct Fees<T> {
+    transport: T,
+    params: Params,
+    history: futures::lock::Mutex<History>,
+}
+
+#[async_trait::async_trait]
+impl<T: Transport + Send + Sync + 'static> Refresh for Fees<T>
+where
+    <T as Transport>::Out: std::marker::Send,
+{
+    type Value = CachedResponse;
+
+    async fn refresh(&self) -> Result<CachedResponse> {
+        let mut history = self.history.lock().await;
+        suggest_fee(self.transport.clone(), &mut history, &self.params).await
+    }
+}
+
+/// `eth_feeHistory` response without rewards. Unlike `web3::types::FeeHistory` this includes the
+/// EIP4844 blob fields which nodes return since the Cancun upgrade.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FeeHistory {
+    oldest_block: BlockNumber,
+    base_fee_per_gas: Vec<U256>,
+    gas_used_ratio: Vec<f64>,
+    #[serde(default)]
+    base_fee_per_blob_gas: Vec<U256>,
+    #[serde(default)]
+    blob_gas_used_ratio: Vec<f64>,
+}
+
+async fn fee_history<T: Transport>(
+    transport: &T,
+    block_count: u64,
+    newest_block: BlockNumber,
+) -> Result<FeeHistory> {
+    let params = vec![
+        helpers::serialize(&U256::from(block_count)),
+        helpers::serialize(&newest_block),
+        helpers::serialize(&Vec::<f64>::new()),
+    ];
+    Ok(CallFuture::new(transport.execute("eth_feeHistory", params)).await?)
+}
+
+/// Fees of a block in the `History`.
+#[derive(Debug, Clone, PartialEq)]
+struct BlockFees {
+    number: u64,
+    base_fee: U256,
+    gas_used_ratio: f64,
+    // zero before the Cancun upgrade
+    blob_base_fee: U256,
+    blob_gas_used_ratio: f64,
+    // priority fees at the `reward_percentiles`, only fetched for the blocks used by
+    // `collect_rewards`
+    rewards: Option<Vec<U256>>,
+}
+
+/// Fee history of the recent blocks. Every update only fetches the blocks after the latest known
+/// one, and the rewards of a block are fetched at most once.
+///
+/// Reorgs are detected when the latest block doesn't build on the known latest block or the base
+/// fee of the known latest block changed, in which case the whole history is fetched again.
+#[derive(Debug, Default)]
+struct History {
+    // consecutive blocks, oldest first
+    blocks: VecDeque<BlockFees>,
+    // base fees of the pending block
+    pending_base_fee: U256,
+    pending_blob_base_fee: U256,
+    // latest block in `blocks`
+    latest_block: Option<Block<H256>>,
+    // block from around the start of the history to observe the block time from
+    reference_block: Option<Block<H256>>,
+}
+
+impl History {
+    // Fetches the blocks up to the latest one which is returned. A latest block behind the known
+    // one, as served by lagging nodes behind a load balancer, leaves the history unchanged and the
+    // known latest block is returned.
+    async fn update<T: Transport>(
+        &mut self,
+        transport: &T,
+        params: &Params,
+    ) -> Result<Block<H256>> {
+        let latest_block = web3::Web3::new(transport.clone())
+            .eth()
+            .block(BlockNumber::Latest.into())
+            .await?
+            .ok_or_else(|| Error::parse("missing latest block"))?;
+        let latest = latest_block
+            .number
+            .ok_or_else(|| Error::parse("missing latest block number"))?
+            .as_u64();
+        let window = params.fee_history_blocks.max(1);
+        if self.is_reorg(&latest_block, latest) {
+            tracing::debug!(latest, "reorg detected through the latest block");
+            self.blocks.clear();
+        }
+        if let (Some(last), Some(known)) = (self.blocks.back(), &self.latest_block) {
+            if latest < last.number {
+                tracing::debug!(latest, known = last.number, "node is behind the history");
+                return Ok(known.clone());
+            }
+        }
+
+        while self.blocks.back().map(|block| block.number) != Some(latest) {
+            // The known latest block is fetched again to check that it didn't change.
+            let first = match self.blocks.back() {
+                Some(last) if last.number < latest && latest - last.number < window => last.number,
+                _ => {
+                    self.blocks.clear();
+                    (latest + 1).saturating_sub(window)
                 }
+            };
+            let fee_history = fee_history(
+                transport,
+                latest - first + 1,
+                BlockNumber::Number(latest.into()),
+            )
+            .await?;
+            if fee_history.gas_used_ratio.len() as u64 != latest - first + 1 {
+                return Err(Error::parse("fee history does not reach the latest block"));
             }
-        });
+            if !self.extend(first, &fee_history)? {
+                tracing::debug!(latest, "reorg detected through the base fee");
+            }
+        }
+        while self.blocks.len() as u64 > window {
+            self.blocks.pop_front();
+        }
+        self.latest_block = Some(latest_block.clone());
+        Ok(latest_block)
+    }
 
-        Ok(Self {
-            cached_response,
-            handle,
-        })
+    // Whether the latest block conflicts with the known latest block. Blocks more than one block
+    // behind or ahead of it can't be compared by hash.
+    fn is_reorg(&self, latest_block: &Block<H256>, latest: u64) -> bool {
+        let (Some(last), Some(known)) = (self.blocks.back(), &self.latest_block) else {
+            return false;
+        };
+        let Some(hash) = known.hash else {
+            return false;
+        };
+        (latest + 1 == last.number && latest_block.hash != Some(known.parent_hash))
+            || (latest == last.number && latest_block.hash != Some(hash))
+            || (latest == last.number + 1 && latest_block.parent_hash != hash)
+    }
+
+    // Appends the blocks of a fee history starting at `first`, which is either the known latest
+    // block or the history is empty. Returns false and clears the history if the known latest
+    // block differs from the fetched one.
+    fn extend(&mut self, first: u64, fee_history: &FeeHistory) -> Result<bool> {
+        if fee_history.oldest_block != BlockNumber::Number(first.into()) {
+            return Err(Error::parse("unexpected oldest block"));
+        }
+        if fee_history.base_fee_per_gas.len() != fee_history.gas_used_ratio.len() + 1 {
+            return Err(Error::parse("base fees not paired with gas used ratios"));
+        }
+        let mut blocks =
+            fee_history
+                .gas_used_ratio
+                .iter()
+                .enumerate()
+                .map(|(i, gas_used_ratio)| BlockFees {
+                    number: first + i as u64,
+                    base_fee: fee_history.base_fee_per_gas[i],
+                    gas_used_ratio: *gas_used_ratio,
+                    blob_base_fee: fee_history
+                        .base_fee_per_blob_gas
+                        .get(i)
+                        .copied()
+                        .unwrap_or_default(),
+                    blob_gas_used_ratio: fee_history
+                        .blob_gas_used_ratio
+                        .get(i)
+                        .copied()
+                        .unwrap_or_default(),
+                    rewards: None,
+                });
+        if let Some(last) = self.blocks.back() {
+            let unchanged = blocks.next().is_some_and(|block| {
+                block.base_fee == last.base_fee
+                    && block.gas_used_ratio == last.gas_used_ratio
+                    && block.blob_base_fee == last.blob_base_fee
+            });
+            if !unchanged {
+                self.blocks.clear();
+                return Ok(false);
+            }
+        }
+        self.blocks.extend(blocks);
+        self.pending_base_fee = fee_history
+            .base_fee_per_gas
+            .last()
+            .copied()
+            .unwrap_or_default();
+        self.pending_blob_base_fee = fee_history
+            .base_fee_per_blob_gas
+            .last()
+            .copied()
+            .unwrap_or_default();
+        Ok(true)
+    }
+
+    // The history in the shape of an `eth_feeHistory` response.
+    fn fee_history(&self) -> FeeHistory {
+        let oldest_block = self.blocks.front().map_or(0, |block| block.number);
+        FeeHistory {
+            oldest_block: BlockNumber::Number(oldest_block.into()),
+            base_fee_per_gas: self
+                .blocks
+                .iter()
+                .map(|block| block.base_fee)
+                .chain(std::iter::once(self.pending_base_fee))
+                .collect(),
+            gas_used_ratio: self
+                .blocks
+                .iter()
+                .map(|block| block.gas_used_ratio)
+                .collect(),
+            base_fee_per_blob_gas: self
+                .blocks
+                .iter()
+                .map(|block| block.blob_base_fee)
+                .chain(std::iter::once(self.pending_blob_base_fee))
+                .collect(),
+            blob_gas_used_ratio: self
+                .blocks
+                .iter()
+                .map(|block| block.blob_gas_used_ratio)
+                .collect(),
+        }
+    }
+
+    // Block `oldest` or an earlier one, refetched once it is more than a history length behind.
+    async fn reference_block<T: Transport>(
+        &mut self,
+        transport: &T,
+        oldest: u64,
+        params: &Params,
+    ) -> Result<Block<H256>> {
+        let outdated = |block: &Block<H256>| {
+            block
+                .number
+                .is_none_or(|number| number.as_u64() + params.fee_history_blocks < oldest)
+        };
+        match &self.reference_block {
+            Some(block) if !outdated(block) => Ok(block.clone()),
+            _ => {
+                let block = web3::Web3::new(transport.clone())
+                    .eth()
+                    .block(BlockNumber::Number(oldest.into()).into())
+                    .await?
+                    .ok_or_else(|| Error::parse("missing oldest block"))?;
+                self.reference_block = Some(block.clone());
+                Ok(block)
+            }
+        }
+    }
+
+    fn block_mut(&mut self, number: u64) -> Option<&mut BlockFees> {
+        let index = number.checked_sub(self.blocks.front()?.number)?;
+        self.blocks.get_mut(index as usize)
     }
 }
 
@@ -177,64 +599,94 @@ impl NativeGasEstimator {
 // meaningful estimates on variable time scales.
 async fn suggest_fee<T: Transport + Send + Sync>(
     transport: T,
+    history: &mut History,
     params: &Params,
-) -> Result<Vec<(f64, GasPrice1559)>> {
-    let web3 = web3::Web3::new(transport.clone());
-    let fee_history = web3
-        .eth()
-        .fee_history(
-            params.fee_history_blocks.into(),
-            serde_json::from_value::<BlockNumber>("latest".into()).unwrap(),
-            Some(vec![]),
-        )
-        .await?;
-
-    // Initialize
-    let mut base_fee = fee_history.base_fee_per_gas.clone();
-    let mut order = (0..fee_history.base_fee_per_gas.len()).collect::<Vec<_>>();
-
-    // If a block is full then the baseFee of the next block is copied. The reason is that in full blocks the minimal
-    // priority fee might not be enough to get included. The last (pending) block is also assumed to end up being full
-    // in order to give some upwards bias for urgent suggestions.
-    ensure!(
-        fee_history.base_fee_per_gas.len() == fee_history.gas_used_ratio.len() + 1,
-        "base_fee_per_gas not paired with gas_used_ratio"
-    );
-    base_fee[fee_history.base_fee_per_gas.len() - 1] *= 9 / 8;
-    for (i, gas_ratio_used) in fee_history.gas_used_ratio.iter().enumerate().rev() {
-        if *gas_ratio_used > 0.9 {
-            base_fee[i] = base_fee[i + 1];
-        }
-    }
+) -> Result<CachedResponse> {
+    let latest_block = history.update(&transport, params).await?;
+    let fee_history = history.fee_history();
 
-    order.sort_by(|a, b| base_fee[*a].cmp(&base_fee[*b]));
+    let (base_fee, order) = sample_base_fees(
+        &fee_history.base_fee_per_gas,
+        &fee_history.gas_used_ratio,
+        &params.base_fee,
+    )?;
+    // Nodes return zeroes for blocks before the Cancun upgrade.
+    let blob_base_fee = if fee_history
+        .base_fee_per_blob_gas
+        .iter()
+        .any(|fee| !fee.is_zero())
+    {
+        // The blob base fee rises by at most about 12.5% per block like the mainnet base fee, which
+        // is also assumed for its worst-case growth below.
+        Some(sample_base_fees(
+            &fee_history.base_fee_per_blob_gas,
+            &fee_history.blob_gas_used_ratio,
+            &base_fee::Params::MAINNET,
+        )?)
+    } else {
+        None
+    };
 
     let oldest_block = if let BlockNumber::Number(x) = fee_history.oldest_block {
         x.as_u64()
     } else {
-        return Err(anyhow!("invalid oldest block"));
+        return Err(Error::parse("invalid oldest block"));
     };
 
-    let rewards =
-        match collect_rewards(transport, oldest_block, fee_history.gas_used_ratio, params).await {
-            Ok(rewards) => rewards,
-            Err(err) => {
-                tracing::warn!(
-                    ?err,
-                    "Failed to collect rewards possibly,\
+    let recent_gas_used_ratio = &fee_history.gas_used_ratio[fee_history
+        .gas_used_ratio
+        .len()
+        .saturating_sub(params.block_space_blocks)..];
+    let gas_used_ratio =
+        recent_gas_used_ratio.iter().sum::<f64>() / recent_gas_used_ratio.len().max(1) as f64;
+    let block_time = match params.block_time {
+        Some(block_time) => block_time,
+        None => {
+            let first_block = history
+                .reference_block(&transport, oldest_block, params)
+                .await?;
+            observed_block_time(&first_block, &latest_block)
+                .ok_or_else(|| Error::parse("unknown block time"))?
+        }
+    };
+
+    let (rewards, block_space) = match collect_rewards(&transport, history, params).await {
+        Ok(rewards) => (
+            rewards.priority_fees,
+            Some(BlockSpace {
+                block_gas_limit: latest_block.gas_limit.to_f64_lossy(),
+                gas_used_ratio,
+                rewards: rewards.distribution,
+            }),
+        ),
+        Err(err) => {
+            tracing::warn!(
+                ?err,
+                "Failed to collect rewards possibly,\
                      because node doesn't follow the spec. \
                      Proceeding with fallback priority fee."
-                );
-                vec![]
-            }
-        };
+            );
+            (vec![], None)
+        }
+    };
     let mut result = vec![];
-    let mut max_base_fee = 0.0;
+    let mut max_base_fee = U256::zero();
+    let mut max_blob_base_fee = U256::zero();
+    let pending_base_fee = fee_history
+        .base_fee_per_gas
+        .last()
+        .copied()
+        .unwrap_or_default();
+    let pending_blob_base_fee = fee_history
+        .base_fee_per_blob_gas
+        .last()
+        .copied()
+        .unwrap_or_default();
     let mut time_factor = params.max_time_factor;
     while time_factor >= 1.0 {
         let priority_fee = suggest_priority_fee(&rewards, time_factor, params);
         let mut min_base_fee = predict_min_base_fee(&base_fee, &order, time_factor - 1.0, params);
-        let mut extra_fee = 0.0;
+        let mut extra_fee = U256::zero();
         if min_base_fee > max_base_fee {
             max_base_fee = min_base_fee;
         } else {
@@ -242,67 +694,189 @@ async fn suggest_fee<T: Transport + Send + Sync>(
             // price dip. In this case getting included with a low priority fee is not guaranteed; instead we use the higher
             // base fee suggestion and also offer extra priority fee to increase the chance of getting included in the base
             // fee dip.
-            extra_fee = (max_base_fee - min_base_fee) * params.extra_priority_fee_ratio;
+            extra_fee = mul_f64_floor(max_base_fee - min_base_fee, params.extra_priority_fee_ratio);
             min_base_fee = max_base_fee;
         }
-        result.push((
-            time_factor,
-            GasPrice1559 {
-                base_fee_per_gas: fee_history
-                    .base_fee_per_gas
-                    .last()
-                    .copied()
-                    .unwrap_or_default()
-                    .low_u64() as f64,
-                max_fee_per_gas: min_base_fee + priority_fee,
-                max_priority_fee_per_gas: priority_fee + extra_fee,
-            },
-        ));
+        // The worst-case base fee until the deadline is added per request in
+        // `estimate_with_request` since it grows exponentially with the deadline.
+        let max_priority_fee = priority_fee + extra_fee;
+        let gas_price = GasPrice::from(GasPrice1559 {
+            base_fee_per_gas: pending_base_fee,
+            max_fee_per_gas: min_base_fee.saturating_add(max_priority_fee),
+            max_priority_fee_per_gas: max_priority_fee,
+        });
+        // Blobs do not pay a priority fee so in a price dip the higher base fee suggestion is used
+        // without any extra fee.
+        let gas_price = match &blob_base_fee {
+            Some((blob_base_fee, blob_order)) => {
+                let min_blob_base_fee =
+                    predict_min_base_fee(blob_base_fee, blob_order, time_factor - 1.0, params);
+                max_blob_base_fee = max_blob_base_fee.max(min_blob_base_fee);
+                gas_price.with_blob_fee(max_blob_base_fee)
+            }
+            None => gas_price,
+        };
+        result.push((time_factor, gas_price));
 
         time_factor /= 2.0;
     }
     result.reverse();
-    Ok(result)
+    Ok(CachedResponse {
+        data: result,
+        block_space,
+        block_time,
+        block_number: latest_block
+            .number
+            .ok_or_else(|| Error::parse("missing latest block number"))?
+            .as_u64(),
+        pending_blob_base_fee,
+        base_fee: params.base_fee,
+        max_fee_blocks: params.max_fee_blocks,
+    })
+}
+
+// Highest base fee that a transaction included within `blocks` blocks, starting with the pending
+// block, may have to pay. Capped at `max_fee_blocks` blocks of growth.
+fn deadline_base_fee(
+    pending_base_fee: U256,
+    blocks: f64,
+    base_fee_params: &base_fee::Params,
+    max_fee_blocks: u64,
+) -> U256 {
+    let blocks = ((blocks.ceil() - 1.0).max(0.0) as u64).min(max_fee_blocks);
+    base_fee_params.max_base_fee_after(pending_base_fee, blocks)
+}
+
+// Average time between blocks from `first` to `last`.
+fn observed_block_time<T>(first: &Block<T>, last: &Block<T>) -> Option<Duration> {
+    let blocks = last.number?.checked_sub(first.number?)?.as_u64();
+    let time = last.timestamp.checked_sub(first.timestamp)?.as_u64();
+    if blocks == 0 || time == 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(time as f64 / blocks as f64))
 }
 
+// Prepares a base fee history for `predict_min_base_fee`. Returns the adjusted base fees and their
+// indices sorted by base fee.
+// If a block is full then the baseFee of the next block is copied. The reason is that in full blocks the minimal
+// priority fee might not be enough to get included. The last (pending) block is also assumed to end up being full
+// in order to give some upwards bias for urgent suggestions.
+fn sample_base_fees(
+    base_fee: &[U256],
+    gas_used_ratio: &[f64],
+    params: &base_fee::Params,
+) -> Result<(Vec<U256>, Vec<usize>)> {
+    if base_fee.len() != gas_used_ratio.len() + 1 {
+        return Err(Error::parse("base fees not paired with gas used ratios"));
+    }
+    let mut base_fee = base_fee.to_vec();
+    let mut order = (0..base_fee.len()).collect::<Vec<_>>();
+
+    let last = base_fee.len() - 1;
+    base_fee[last] = params.max_base_fee_after(base_fee[last], 1);
+    for (i, gas_ratio_used) in gas_used_ratio.iter().enumerate().rev() {
+        if *gas_ratio_used > 0.9 {
+            base_fee[i] = base_fee[i + 1];
+        }
+    }
+
+    order.sort_by(|a, b| base_fee[*a].cmp(&base_fee[*b]));
+    Ok((base_fee, order))
+}
+
+struct Rewards {
+    // sorted non-zero rewards up to max_reward_percentile of the sampled blocks
+    priority_fees: Vec<U256>,
+    // gas weighted reward distribution, for each of the `reward_percentiles` the median reward of
+    // the sampled blocks
+    distribution: Vec<U256>,
+}
+
+// Reward percentiles requested from `eth_feeHistory`. Geth rejects requests for more than 100
+// percentiles, so only the lower half where the priority fee suggestions come from is requested
+// for every percentile and the upper half for every second one.
+fn reward_percentiles() -> Vec<f64> {
+    (0..=50)
+        .chain((52..=100).step_by(2))
+        .map(f64::from)
+        .collect()
+}
+
+// Rewards of the blocks in the history are reused, only the blocks without rewards are fetched.
 async fn collect_rewards<T: Transport + Send + Sync>(
-    transport: T,
-    first_block: u64,
-    gas_used_ratio: Vec<f64>,
+    transport: &T,
+    history: &mut History,
     params: &Params,
-) -> Result<Vec<u64>> {
-    let mut percentiles = vec![];
-    for i in 0..=params.max_reward_percentile {
-        percentiles.push(i as f64);
-    }
+) -> Result<Rewards> {
+    // The whole range is requested for the gas weighted reward distribution, the priority fee
+    // suggestion only uses the percentiles up to max_reward_percentile.
+    let percentiles = reward_percentiles();
 
-    let mut ptr = gas_used_ratio.len() - 1;
+    let gas_used_ratio = history
+        .blocks
+        .iter()
+        .map(|block| block.gas_used_ratio)
+        .collect::<Vec<_>>();
+    let mut ptr = gas_used_ratio.len().checked_sub(1).ok_or(Error::NoData)?;
     let mut need_blocks: usize = 5;
     let mut rewards = vec![];
+    let mut block_rewards = vec![];
     while need_blocks > 0 {
         let block_count = max_block_count(&gas_used_ratio, ptr, need_blocks)?;
         if block_count > 0 {
-            // feeHistory API call with reward percentile specified is expensive and therefore is only requested for a few
-            // non-full recent blocks.
-            let web3 = web3::Web3::new(transport.clone());
-            let fee_history = web3
-                .eth()
-                .fee_history(
-                    block_count.into(),
-                    (first_block + ptr as u64).into(),
-                    Some(percentiles.clone()),
-                )
-                .await?;
+            let blocks = ptr + 1 - block_count..=ptr;
+            let missing = blocks
+                .clone()
+                .filter(|i| history.blocks[*i].rewards.is_none())
+                .collect::<Vec<_>>();
+            if let (Some(first), Some(last)) = (missing.first(), missing.last()) {
+                // feeHistory API call with reward percentile specified is expensive and therefore is only requested for a few
+                // non-full recent blocks.
+                let web3 = web3::Web3::new(transport.clone());
+                let fee_history = web3
+                    .eth()
+                    .fee_history(
+                        (last - first + 1).into(),
+                        history.blocks[*last].number.into(),
+                        Some(percentiles.clone()),
+                    )
+                    .await?;
 
-            let fee_history_reward = fee_history.reward.context("missing reward in response")?;
-            rewards.extend(
-                fee_history_reward
-                    .iter()
-                    .flatten()
-                    .map(U256::low_u64)
-                    .filter(|reward| *reward > 0),
-            );
-            if fee_history_reward.len() < block_count {
+                let oldest_block = match fee_history.oldest_block {
+                    BlockNumber::Number(number) => number.as_u64(),
+                    _ => return Err(Error::parse("invalid oldest block")),
+                };
+                let fee_history_reward = fee_history
+                    .reward
+                    .ok_or_else(|| Error::parse("missing reward in response"))?;
+                for (number, reward) in (oldest_block..).zip(fee_history_reward) {
+                    if let Some(block) = history.block_mut(number) {
+                        block.rewards = Some(reward);
+                    }
+                }
+            }
+
+            let mut complete = true;
+            for i in blocks {
+                match &history.blocks[i].rewards {
+                    Some(block) => {
+                        rewards.extend(
+                            percentiles
+                                .iter()
+                                .zip(block)
+                                .filter(|(percentile, reward)| {
+                                    **percentile <= params.max_reward_percentile as f64
+                                        && !reward.is_zero()
+                                })
+                                .map(|(_, reward)| *reward),
+                        );
+                        block_rewards.push(block.clone());
+                    }
+                    None => complete = false,
+                }
+            }
+            if !complete {
                 break;
             }
 
@@ -316,16 +890,32 @@ async fn collect_rewards<T: Transport + Send + Sync>(
     }
 
     rewards.sort_unstable();
-    Ok(rewards)
+    Ok(Rewards {
+        priority_fees: rewards,
+        distribution: reward_distribution(&block_rewards),
+    })
+}
+
+// Median of the rewards of the individual blocks for each percentile.
+fn reward_distribution(block_rewards: &[Vec<U256>]) -> Vec<U256> {
+    (0..reward_percentiles().len())
+        .map_while(|percentile| {
+            let mut rewards = block_rewards
+                .iter()
+                .filter_map(|block| block.get(percentile).copied())
+                .collect::<Vec<_>>();
+            rewards.sort_unstable();
+            rewards.get(rewards.len().checked_sub(1)? / 2).copied()
+        })
+        .collect()
 }
 
 // maxBlockCount returns the number of consecutive blocks suitable for priority fee suggestion (gasUsedRatio non-zero
 // and not higher than 0.9).
 fn max_block_count(gas_used_ratio: &[f64], last_index: usize, need_blocks: usize) -> Result<usize> {
-    ensure!(
-        gas_used_ratio.len() > last_index,
-        "max_block_count invalid input"
-    );
+    if gas_used_ratio.len() <= last_index {
+        return Err(Error::validation("max_block_count invalid input"));
+    }
     Ok((0..std::cmp::min(last_index + 1, need_blocks))
         .take_while(|i| {
             !(gas_used_ratio[last_index - i] == 0.0 || gas_used_ratio[last_index - i] > 0.9)
@@ -335,7 +925,7 @@ fn max_block_count(gas_used_ratio: &[f64], last_index: usize, need_blocks: usize
 
 // suggestPriorityFee suggests a priority fee (maxPriorityFeePerGas) value that's usually sufficient for blocks that
 // are not full.
-fn suggest_priority_fee(rewards: &[u64], time_factor: f64, params: &Params) -> f64 {
+fn suggest_priority_fee(rewards: &[U256], time_factor: f64, params: &Params) -> U256 {
     if rewards.is_empty() {
         return params.fallback_priority_fee;
     }
@@ -344,27 +934,34 @@ fn suggest_priority_fee(rewards: &[u64], time_factor: f64, params: &Params) -> f
         + (params.max_block_percentile - params.min_block_percentile) / time_factor)
         / 100.0;
     let index = ((rewards.len() - 1) as f64 * factor).floor() as usize;
-    rewards[index] as f64 + params.extra_priority_fee_boost
+    rewards[index].saturating_add(params.extra_priority_fee_boost)
 }
 
 // predictMinBaseFee calculates an average of base fees in the sampleMinPercentile to sampleMaxPercentile percentile
 // range of recent base fee history, each block weighted with an exponential time function based on timeFactor.
-fn predict_min_base_fee(base_fee: &[U256], order: &[usize], time_div: f64, params: &Params) -> f64 {
+fn predict_min_base_fee(
+    base_fee: &[U256],
+    order: &[usize],
+    time_div: f64,
+    params: &Params,
+) -> U256 {
     if time_div < 1e-6 {
-        return base_fee.last().copied().unwrap_or_default().low_u64() as f64;
+        return base_fee.last().copied().unwrap_or_default();
     }
 
     let pending_weight =
         (1.0 - E.powf(-1.0 / time_div)) / (1.0 - E.powf(-(base_fee.len() as f64) / time_div));
     let mut sum_weight = 0.0;
-    let mut result = 0.0;
+    let mut result = U256::zero();
     let mut sampling_curve_last = 0.0;
     for order_elem in order {
         sum_weight +=
             pending_weight * E.powf((*order_elem as f64 - base_fee.len() as f64 + 1.0) / time_div);
         let sampling_curve_value = sampling_curve(sum_weight * 100.0, params);
-        result +=
-            (sampling_curve_value - sampling_curve_last) * base_fee[*order_elem].low_u64() as f64;
+        result += mul_f64_floor(
+            base_fee[*order_elem],
+            sampling_curve_value - sampling_curve_last,
+        );
         if sampling_curve_value >= 1.0 {
             return result;
         }
@@ -391,63 +988,129 @@ fn sampling_curve(percentile: f64, params: &Params) -> f64 {
 
 #[async_trait::async_trait]
 impl GasPriceEstimating for NativeGasEstimator {
-    async fn estimate_with_limits(
-        &self,
-        _gas_limit: f64,
-        time_limit: Duration,
-    ) -> Result<GasPrice1559> {
-        let cached_response = self.cached_response.lock().unwrap().clone();
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
+        let request = EstimationRequest::default()
+            .with_gas_limit(gas_limit)
+            .with_time_limit(time_limit);
+        estimate_with_request(request, self.cache.get()?.0)
+    }
 
-        estimate_with_limits(time_limit, cached_response)
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        Ok(self.estimate_detailed(request).await?.gas_price)
     }
-}
 
-fn estimate_with_limits(
-    time_limit: Duration,
-    cached_response: CachedResponse,
-) -> Result<GasPrice1559> {
-    if Instant::now().saturating_duration_since(cached_response.time) > CACHED_RESPONSE_VALIDITY {
-        return Err(anyhow!("cached response is stale"));
+    // Honours `target_block` in addition to the fields handled by `EstimationRequest::apply`.
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        let (cached_response, age) = self.cache.get()?;
+
+        let block_number = cached_response.block_number;
+        let gas_price = request.apply(estimate_with_request(request, cached_response)?)?;
+        Ok(Estimate::new(gas_price, NAME)
+            .with_block_number(block_number)
+            .with_age(age))
     }
+}
 
+fn estimate_with_request(
+    request: EstimationRequest,
+    cached_response: CachedResponse,
+) -> Result<GasPrice> {
     if cached_response.data.is_empty() {
-        return Err(anyhow!("no cached data exist"));
+        return Err(Error::NoData);
     }
 
+    // The cached curve is keyed by time factor, which is a number of blocks.
+    let blocks = match request.target_block {
+        Some(target_block) => target_block.saturating_sub(cached_response.block_number) as f64,
+        None if cached_response.block_time.is_zero() => {
+            return Err(Error::validation("unknown block time"))
+        }
+        None => request.time_limit.as_secs_f64() / cached_response.block_time.as_secs_f64(),
+    };
+
     let max_fee_per_gas_points = cached_response
         .data
         .iter()
-        .map(|(time_limit, gas_price)| (*time_limit, gas_price.max_fee_per_gas))
-        .collect::<Vec<(f64, f64)>>();
+        .map(|(time_factor, gas_price)| (*time_factor, gas_price.to_eip1559().max_fee_per_gas))
+        .collect::<Vec<(f64, U256)>>();
     let max_priority_fee_per_gas_points = cached_response
         .data
         .iter()
-        .map(|(time_limit, gas_price)| (*time_limit, gas_price.max_priority_fee_per_gas))
-        .collect::<Vec<(f64, f64)>>();
-    let base_fee_per_gas = cached_response.data[0].1.base_fee_per_gas;
+        .map(|(time_factor, gas_price)| {
+            (
+                *time_factor,
+                gas_price.to_eip1559().max_priority_fee_per_gas,
+            )
+        })
+        .collect::<Vec<(f64, U256)>>();
+    let max_fee_per_blob_gas_points = cached_response
+        .data
+        .iter()
+        .map(|(time_factor, gas_price)| Some((*time_factor, gas_price.max_fee_per_blob_gas()?)))
+        .collect::<Option<Vec<(f64, U256)>>>();
+    let base_fee_per_gas = cached_response.data[0].1.to_eip1559().base_fee_per_gas;
 
-    GasPrice1559 {
+    let mut gas_price = GasPrice1559 {
         max_fee_per_gas: linear_interpolation::interpolate(
-            time_limit.as_secs_f64(),
+            blocks,
             max_fee_per_gas_points.as_slice().try_into()?,
         ),
         max_priority_fee_per_gas: linear_interpolation::interpolate(
-            time_limit.as_secs_f64(),
+            blocks,
             max_priority_fee_per_gas_points.as_slice().try_into()?,
         ),
         base_fee_per_gas,
+    };
+    // Transactions using a large share of the block need to outbid the transactions they displace.
+    if let Some(priority_fee) = cached_response
+        .block_space
+        .as_ref()
+        .and_then(|block_space| block_space.priority_fee(request.gas_limit))
+    {
+        if priority_fee > gas_price.max_priority_fee_per_gas {
+            let extra_fee = priority_fee - gas_price.max_priority_fee_per_gas;
+            gas_price.max_priority_fee_per_gas = priority_fee;
+            gas_price.max_fee_per_gas = gas_price.max_fee_per_gas.saturating_add(extra_fee);
+        }
     }
-    .validate()
+    // The max fee covers the base fee even if all blocks until the deadline are full, so that the
+    // transaction stays valid until then.
+    let max_base_fee_until_deadline = deadline_base_fee(
+        base_fee_per_gas,
+        blocks,
+        &cached_response.base_fee,
+        cached_response.max_fee_blocks,
+    );
+    gas_price.max_fee_per_gas = gas_price
+        .max_fee_per_gas
+        .max(max_base_fee_until_deadline.saturating_add(gas_price.max_priority_fee_per_gas));
+    let gas_price = gas_price.validate()?;
+
+    // The blob base fee rises by at most about 12.5% per block like the mainnet base fee.
+    Ok(match max_fee_per_blob_gas_points {
+        Some(points) => GasPrice::from(gas_price).with_blob_fee(
+            linear_interpolation::interpolate(blocks, points.as_slice().try_into()?).max(
+                deadline_base_fee(
+                    cached_response.pending_blob_base_fee,
+                    blocks,
+                    &base_fee::Params::MAINNET,
+                    cached_response.max_fee_blocks,
+                ),
+            ),
+        ),
+        None => gas_price.into(),
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::tests::TestTransport;
+    use crate::tests::{TestTransport, Web3TestTransport};
 
     use super::*;
 
     use super::super::blocknative::BlockNative;
     use super::NativeGasEstimator;
+    use crate::{FeeModel, DEFAULT_GAS_LIMIT};
     use assert_approx_eq::assert_approx_eq;
     use std::time::Duration;
     use std::{fs::File, io::Write};
@@ -498,6 +1161,548 @@ mod tests {
         }
     }
 
+    fn cached_response(blob_fees: Option<[u64; 2]>) -> CachedResponse {
+        let data = [(1.0, 100u64, 10u64), (4.0, 70, 4)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (time_factor, max_fee, priority_fee))| {
+                let gas_price = GasPrice::from(GasPrice1559 {
+                    base_fee_per_gas: 50.into(),
+                    max_fee_per_gas: max_fee.into(),
+                    max_priority_fee_per_gas: priority_fee.into(),
+                });
+                let gas_price = match blob_fees {
+                    Some(blob_fees) => gas_price.with_blob_fee(blob_fees[i].into()),
+                    None => gas_price,
+                };
+                (time_factor, gas_price)
+            })
+            .collect();
+        CachedResponse {
+            data,
+            block_space: None,
+            block_time: Duration::from_secs(1),
+            block_number: 100,
+            pending_blob_base_fee: 5.into(),
+            base_fee: base_fee::Params::MAINNET,
+            max_fee_blocks: 127,
+        }
+    }
+
+    fn block_json(number: u64, hash: u64, parent_hash: u64) -> serde_json::Value {
+        serde_json::to_value(Block::<H256> {
+            number: Some(number.into()),
+            hash: Some(H256::from_low_u64_be(hash)),
+            parent_hash: H256::from_low_u64_be(parent_hash),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    // Fee history of blocks 15 and 16 with blob fees.
+    fn web3_transport() -> Web3TestTransport {
+        let transport = Web3TestTransport::default();
+        transport.add_response("eth_getBlockByNumber", block_json(16, 16, 15));
+        transport.add_response(
+            "eth_feeHistory",
+            serde_json::json!({
+                "oldestBlock": "0xf",
+                "baseFeePerGas": ["0x64", "0x64", "0x64"],
+                "gasUsedRatio": [0.5, 0.5],
+                "baseFeePerBlobGas": ["0x1", "0x1", "0x1"],
+                "blobGasUsedRatio": [0.5, 0.5],
+            }),
+        );
+        transport
+    }
+
+    fn web3_params() -> Params {
+        Params {
+            fee_history_blocks: 2,
+            block_time: Some(Duration::from_secs(12)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn estimate_keeps_blob_fees() {
+        let estimator = NativeGasEstimator::new(web3_transport(), Some(web3_params()))
+            .await
+            .unwrap();
+        let gas_price = estimator.estimate().await.unwrap();
+        assert_eq!(gas_price.fee_model(), FeeModel::Eip4844);
+        let gas_price = estimator
+            .estimate_with_request(EstimationRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(gas_price.fee_model(), FeeModel::Eip4844);
+        let gas_price = estimator
+            .estimate_with_request(EstimationRequest::default().with_blobs(false))
+            .await
+            .unwrap();
+        assert_eq!(gas_price.fee_model(), FeeModel::Eip1559);
+    }
+
+    fn request(gas_limit: f64, time_limit: Duration) -> EstimationRequest {
+        EstimationRequest::default()
+            .with_gas_limit(gas_limit)
+            .with_time_limit(time_limit)
+    }
+
+    #[test]
+    fn estimate_with_limits_without_blob_fees() {
+        let price = estimate_with_request(
+            request(DEFAULT_GAS_LIMIT, Duration::from_millis(2500)),
+            cached_response(None),
+        )
+        .unwrap();
+        assert_eq!(
+            price,
+            GasPrice::Eip1559(GasPrice1559 {
+                base_fee_per_gas: 50.into(),
+                max_fee_per_gas: 85.into(),
+                max_priority_fee_per_gas: 7.into(),
+            })
+        );
+    }
+
+    #[test]
+    fn estimate_with_limits_interpolates_blob_fees() {
+        let price = estimate_with_request(
+            request(DEFAULT_GAS_LIMIT, Duration::from_millis(2500)),
+            cached_response(Some([40, 10])),
+        )
+        .unwrap();
+        assert_eq!(price.fee_model(), FeeModel::Eip4844);
+        assert_eq!(price.max_fee_per_blob_gas(), Some(25.into()));
+        assert_eq!(price.to_eip1559().max_fee_per_gas, 85.into());
+    }
+
+    #[test]
+    fn estimate_with_limits_converts_time_limit_into_blocks() {
+        for (block_time, time_limit) in [(12, 30_000), (5, 12_500), (2, 5_000)] {
+            let cached_response = CachedResponse {
+                block_time: Duration::from_secs(block_time),
+                ..cached_response(None)
+            };
+            let price = estimate_with_request(
+                request(DEFAULT_GAS_LIMIT, Duration::from_millis(time_limit)),
+                cached_response,
+            )
+            .unwrap();
+            // 2.5 blocks
+            assert_eq!(price.to_eip1559().max_fee_per_gas, 85.into());
+        }
+    }
+
+    #[test]
+    fn estimate_with_request_target_block() {
+        let cached_response = CachedResponse {
+            block_time: Duration::from_secs(12),
+            ..cached_response(None)
+        };
+        // the base fee after three full blocks plus the priority fee
+        let request = EstimationRequest::default().with_target_block(104);
+        let price = estimate_with_request(request, cached_response.clone()).unwrap();
+        assert_eq!(price.to_eip1559().max_fee_per_gas, 74.into());
+
+        // blocks in the past clamp to the most urgent estimate
+        let request = EstimationRequest::default().with_target_block(90);
+        let price = estimate_with_request(request, cached_response).unwrap();
+        assert_eq!(price.to_eip1559().max_fee_per_gas, 100.into());
+    }
+
+    // Fires for the heads sent through the channel.
+    struct ChannelHeads {
+        stream: Fuse<futures::channel::mpsc::UnboundedReceiver<web3::Result<BlockHeader>>>,
+        last: Option<H256>,
+    }
+
+    #[async_trait::async_trait]
+    impl Trigger for ChannelHeads {
+        async fn next(&mut self) -> Result<()> {
+            next_head(&mut self.stream, &mut self.last)
+                .await
+                .unwrap_or(Err(Error::NoData))
+        }
+    }
+
+    // Counts the refreshes, each taking 50 ms.
+    #[derive(Clone, Default)]
+    struct SlowRefresh(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl Refresh for SlowRefresh {
+        type Value = ();
+
+        async fn refresh(&self) -> Result<()> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn heads_during_refresh_cause_single_refresh() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let head = |hash: u64| {
+            Ok(BlockHeader {
+                hash: Some(H256::from_low_u64_be(hash)),
+                parent_hash: Default::default(),
+                uncles_hash: Default::default(),
+                author: Default::default(),
+                state_root: Default::default(),
+                transactions_root: Default::default(),
+                receipts_root: Default::default(),
+                number: Some(hash.into()),
+                gas_used: Default::default(),
+                gas_limit: Default::default(),
+                base_fee_per_gas: None,
+                extra_data: Default::default(),
+                logs_bloom: Default::default(),
+                timestamp: Default::default(),
+                difficulty: Default::default(),
+                mix_hash: None,
+                nonce: None,
+            })
+        };
+        let refresh = SlowRefresh::default();
+        let _cache = BackgroundRefresh::with_trigger(
+            NAME,
+            refresh.clone(),
+            background::Config::mainnet(),
+            ChannelHeads {
+                stream: receiver.fuse(),
+                last: None,
+            },
+        )
+        .await
+        .unwrap();
+        let refreshes = || refresh.0.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(refreshes(), 1);
+
+        sender.unbounded_send(head(1)).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(refreshes(), 2);
+        // announced while the second refresh is running
+        for hash in 2..5 {
+            sender.unbounded_send(head(hash)).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(refreshes(), 3);
+    }
+
+    #[test]
+    fn polling_detects_new_blocks() {
+        let mut last = None;
+        let new = [10u64, 10, 11, 9, 11, 13]
+            .into_iter()
+            .map(|block_number| is_new_block(&mut last, block_number.into()))
+            .collect::<Vec<_>>();
+        assert_eq!(new, [false, false, true, false, false, true]);
+    }
+
+    fn base_fees(base_fees: &[u64]) -> Vec<U256> {
+        base_fees.iter().copied().map(U256::from).collect()
+    }
+
+    fn fee_history_from(first: u64, base_fee: &[u64]) -> FeeHistory {
+        FeeHistory {
+            oldest_block: BlockNumber::Number(first.into()),
+            base_fee_per_gas: base_fees(base_fee),
+            gas_used_ratio: vec![0.5; base_fee.len() - 1],
+            base_fee_per_blob_gas: vec![],
+            blob_gas_used_ratio: vec![],
+        }
+    }
+
+    #[test]
+    fn history_extends_incrementally() {
+        let mut history = History::default();
+        assert!(history
+            .extend(10, &fee_history_from(10, &[1, 2, 3]))
+            .unwrap());
+        // the known latest block is fetched again
+        assert!(history
+            .extend(11, &fee_history_from(11, &[2, 3, 4]))
+            .unwrap());
+        let fee_history = history.fee_history();
+        assert_eq!(fee_history.oldest_block, BlockNumber::Number(10.into()));
+        assert_eq!(fee_history.base_fee_per_gas, base_fees(&[1, 2, 3, 4]));
+        assert_eq!(fee_history.gas_used_ratio.len(), 3);
+        assert_eq!(fee_history.base_fee_per_blob_gas, base_fees(&[0, 0, 0, 0]));
+        assert!(history.block_mut(12).is_some());
+        assert!(history.block_mut(13).is_none());
+        assert!(history.extend(13, &fee_history_from(12, &[3, 4])).is_err());
+    }
+
+    #[test]
+    fn history_detects_reorgs() {
+        let mut history = History::default();
+        history
+            .extend(10, &fee_history_from(10, &[1, 2, 3]))
+            .unwrap();
+        // the base fee of the known latest block changed
+        assert!(!history
+            .extend(11, &fee_history_from(11, &[5, 3, 4]))
+            .unwrap());
+        assert!(history.blocks.is_empty());
+
+        history
+            .extend(10, &fee_history_from(10, &[1, 2, 3]))
+            .unwrap();
+        let block = |number: u64, hash: u8, parent_hash: u8| Block::<H256> {
+            number: Some(number.into()),
+            hash: Some(H256::repeat_byte(hash)),
+            parent_hash: H256::repeat_byte(parent_hash),
+            ..Default::default()
+        };
+        history.latest_block = Some(block(11, 1, 0));
+        assert!(!history.is_reorg(&block(11, 1, 0), 11));
+        assert!(history.is_reorg(&block(11, 2, 0), 11));
+        assert!(!history.is_reorg(&block(12, 2, 1), 12));
+        assert!(history.is_reorg(&block(12, 2, 3), 12));
+        // blocks further ahead are checked through the base fee
+        assert!(!history.is_reorg(&block(14, 2, 3), 14));
+        // lagging nodes
+        assert!(!history.is_reorg(&block(10, 0, 3), 10));
+        assert!(history.is_reorg(&block(10, 2, 3), 10));
+        assert!(!history.is_reorg(&block(9, 2, 3), 9));
+    }
+
+    #[tokio::test]
+    async fn history_ignores_lagging_node() {
+        let transport = web3_transport();
+        // block 15 is the parent of block 16
+        transport.add_response("eth_getBlockByNumber", block_json(15, 15, 14));
+        let mut history = History::default();
+        let params = web3_params();
+        let latest = history.update(&transport, &params).await.unwrap();
+        assert_eq!(latest.number, Some(16.into()));
+        assert_eq!(transport.calls("eth_feeHistory"), 1);
+
+        let latest = history.update(&transport, &params).await.unwrap();
+        assert_eq!(latest.number, Some(16.into()));
+        assert_eq!(transport.calls("eth_feeHistory"), 1);
+        assert_eq!(history.blocks.len(), 2);
+    }
+
+    #[test]
+    fn observed_block_time_from_timestamps() {
+        let block = |number: u64, timestamp: u64| Block::<()> {
+            number: Some(number.into()),
+            timestamp: timestamp.into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            observed_block_time(&block(100, 1_000), &block(400, 4_600)),
+            Some(Duration::from_secs(12))
+        );
+        assert_eq!(
+            observed_block_time(&block(100, 1_000), &block(101, 1_002)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            observed_block_time(&block(100, 1_000), &block(100, 1_000)),
+            None
+        );
+        assert_eq!(
+            observed_block_time(&block(100, 1_000), &block(99, 900)),
+            None
+        );
+    }
+
+    #[test]
+    fn estimate_with_limits_fee_rises_with_gas_limit() {
+        let cached_response = CachedResponse {
+            block_space: Some(BlockSpace {
+                block_gas_limit: 30_000_000.0,
+                gas_used_ratio: 0.8,
+                rewards: reward_percentiles()
+                    .into_iter()
+                    .map(|percentile| U256::from(percentile as u64))
+                    .collect(),
+            }),
+            ..cached_response(None)
+        };
+
+        let estimates = [
+            21_000.0,
+            1_000_000.0,
+            6_000_000.0,
+            10_000_000.0,
+            15_000_000.0,
+            30_000_000.0,
+        ]
+        .into_iter()
+        .map(|gas_limit| {
+            estimate_with_request(
+                request(gas_limit, Duration::from_secs(4)),
+                cached_response.clone(),
+            )
+            .unwrap()
+            .to_eip1559()
+        })
+        .collect::<Vec<_>>();
+
+        for window in estimates.windows(2) {
+            assert!(window[0].max_priority_fee_per_gas <= window[1].max_priority_fee_per_gas);
+            assert!(window[0].max_fee_per_gas <= window[1].max_fee_per_gas);
+        }
+        // small transactions fit into the remaining block space and get the regular suggestion,
+        // the max fee covers the base fee after three full blocks
+        assert_eq!(estimates[0].max_priority_fee_per_gas, 4.into());
+        assert_eq!(estimates[0].max_fee_per_gas, 74.into());
+        // half a block displaces (0.8 + 0.5 - 1) / 0.8 = 37.5% of the other transactions' gas
+        assert_eq!(estimates[4].max_priority_fee_per_gas, 37.into());
+        assert_eq!(estimates[4].max_fee_per_gas, 107.into());
+        assert_eq!(estimates[5].max_priority_fee_per_gas, 100.into());
+    }
+
+    #[tokio::test]
+    async fn rewards_request_at_most_100_percentiles() {
+        let transport = web3_transport();
+        NativeGasEstimator::new(transport.clone(), Some(web3_params()))
+            .await
+            .unwrap();
+        let percentiles = transport
+            .params("eth_feeHistory")
+            .into_iter()
+            .filter_map(|params| Some(params.get(2)?.as_array()?.len()))
+            .max()
+            .unwrap();
+        assert_eq!(percentiles, reward_percentiles().len());
+        assert!(percentiles <= 100);
+    }
+
+    #[test]
+    fn reward_distribution_takes_median_per_percentile() {
+        let block_rewards = vec![
+            vec![1.into(), 5.into(), 9.into()],
+            vec![2.into(), 3.into(), 4.into()],
+            vec![0.into(), 7.into(), 8.into()],
+        ];
+        assert_eq!(
+            reward_distribution(&block_rewards),
+            vec![1.into(), 5.into(), 8.into()]
+        );
+        assert!(reward_distribution(&[]).is_empty());
+    }
+
+    #[test]
+    fn fee_history_with_blob_fields() {
+        let fee_history: FeeHistory = serde_json::from_value(serde_json::json!({
+            "oldestBlock": "0x10",
+            "baseFeePerGas": ["0x1", "0x2", "0x3"],
+            "gasUsedRatio": [0.5, 0.95],
+            "baseFeePerBlobGas": ["0x1", "0x1", "0x2"],
+            "blobGasUsedRatio": [0.0, 1.0],
+        }))
+        .unwrap();
+        assert_eq!(fee_history.base_fee_per_blob_gas.len(), 3);
+
+        let (blob_base_fee, order) = sample_base_fees(
+            &fee_history.base_fee_per_blob_gas,
+            &fee_history.blob_gas_used_ratio,
+            &base_fee::Params::MAINNET,
+        )
+        .unwrap();
+        // the pending block is assumed to be full
+        assert_eq!(blob_base_fee, vec![1.into(), 3.into(), 3.into()]);
+        assert_eq!(order, vec![0, 1, 2]);
+
+        let fee_history: FeeHistory = serde_json::from_value(serde_json::json!({
+            "oldestBlock": "0x10",
+            "baseFeePerGas": ["0x1", "0x2"],
+            "gasUsedRatio": [0.5],
+        }))
+        .unwrap();
+        assert!(fee_history.base_fee_per_blob_gas.is_empty());
+    }
+
+    #[test]
+    fn sample_base_fees_assumes_full_pending_block() {
+        let base_fee = [800u64, 1000, 1000].map(U256::from);
+        let (base_fee, order) =
+            sample_base_fees(&base_fee, &[0.5, 0.2], &base_fee::Params::MAINNET).unwrap();
+        assert_eq!(base_fee, vec![800.into(), 1000.into(), 1125.into()]);
+        assert_eq!(order, vec![0, 1, 2]);
+
+        let base_fee = [1000u64, 1000].map(U256::from);
+        let (base_fee, _) =
+            sample_base_fees(&base_fee, &[1.0], &base_fee::Params::OPTIMISM).unwrap();
+        assert_eq!(base_fee, vec![1020.into(), 1020.into()]);
+    }
+
+    #[test]
+    fn deadline_base_fee_grows_per_block() {
+        let max_fee_blocks = Params::default().max_fee_blocks;
+        let deadline = |blocks| {
+            deadline_base_fee(
+                800.into(),
+                blocks,
+                &base_fee::Params::MAINNET,
+                max_fee_blocks,
+            )
+        };
+        assert_eq!(deadline(0.5), 800.into());
+        assert_eq!(deadline(1.0), 800.into());
+        assert_eq!(deadline(2.0), 900.into());
+        assert_eq!(deadline(2.5), 1012.into());
+        assert_eq!(deadline(3.0), 1012.into());
+        assert_eq!(
+            deadline(128.0),
+            base_fee::Params::MAINNET.max_base_fee_after(800.into(), 127)
+        );
+        // capped by default
+        assert_eq!(deadline(1000.0), deadline(128.0));
+
+        let deadline = deadline_base_fee(1000.into(), 4.0, &base_fee::Params::OPTIMISM, 127);
+        assert_eq!(deadline, 1060.into());
+    }
+
+    #[test]
+    fn deadline_base_fee_capped_at_max_fee_blocks() {
+        let mainnet = base_fee::Params::MAINNET;
+        let deadline = |blocks, max_fee_blocks| {
+            deadline_base_fee(800.into(), blocks, &mainnet, max_fee_blocks)
+        };
+        // 13 blocks including the pending one are 12 blocks of growth
+        assert_eq!(
+            deadline(13.0, 12),
+            mainnet.max_base_fee_after(800.into(), 12)
+        );
+        assert_eq!(deadline(14.0, 12), deadline(13.0, 12));
+        assert_eq!(deadline(13.0, u64::MAX), deadline(13.0, 12));
+        assert_eq!(
+            deadline(14.0, u64::MAX),
+            mainnet.max_base_fee_after(800.into(), 13)
+        );
+    }
+
+    #[test]
+    fn estimate_covers_exact_deadline_base_fee() {
+        let cached_response = CachedResponse {
+            block_time: Duration::from_secs(12),
+            ..cached_response(Some([40, 10]))
+        };
+        // 25 blocks are between the cached time factors
+        let price = estimate_with_request(
+            EstimationRequest::default().with_time_limit(Duration::from_secs(300)),
+            cached_response,
+        )
+        .unwrap();
+        let mainnet = base_fee::Params::MAINNET;
+        assert_eq!(
+            price.to_eip1559().max_fee_per_gas,
+            mainnet.max_base_fee_after(50.into(), 24) + 4
+        );
+        assert_eq!(
+            price.max_fee_per_blob_gas(),
+            Some(mainnet.max_base_fee_after(5.into(), 24))
+        );
+    }
+
     #[test]
     fn sampling_curve_minimum() {
         assert_approx_eq!(sampling_curve(0.0, &Default::default()), 0.0);
@@ -540,10 +1745,14 @@ mod tests {
         );
     }
 
+    fn rewards(rewards: &[u64]) -> Vec<U256> {
+        rewards.iter().copied().map(U256::from).collect()
+    }
+
     #[test]
     fn suggest_priority_fee_empty_rewards() {
         let params = Default::default();
-        assert_approx_eq!(
+        assert_eq!(
             suggest_priority_fee(&[], 1.0, &params),
             params.fallback_priority_fee
         );
@@ -552,16 +1761,16 @@ mod tests {
     #[test]
     fn suggest_priority_fee_default_params() {
         let params = Default::default();
-        assert_approx_eq!(
+        assert_eq!(
             suggest_priority_fee(
-                &[
+                &rewards(&[
                     1000000000, 1110000000, 1213318421, 1433574636, 1557989644, 1615965689,
                     2000000000, 2557989644, 2910000000, 3000000000
-                ],
+                ]),
                 1.0,
                 &params
             ),
-            1615967248.0
+            U256::from(1615967248u64)
         );
     }
 
@@ -570,19 +1779,19 @@ mod tests {
         let params = Params {
             min_block_percentile: 0.0,
             max_block_percentile: 0.0,
-            extra_priority_fee_boost: 0.0,
+            extra_priority_fee_boost: 0.into(),
             ..Default::default()
         };
-        assert_approx_eq!(
+        assert_eq!(
             suggest_priority_fee(
-                &[
+                &rewards(&[
                     1000000000, 1110000000, 1213318421, 1433574636, 1557989644, 1615965689,
                     2000000000, 2557989644, 2910000000, 3000000000
-                ],
+                ]),
                 1.0,
                 &params
             ),
-            1000000000.0
+            U256::from(1000000000u64)
         );
     }
 
@@ -591,19 +1800,19 @@ mod tests {
         let params = Params {
             min_block_percentile: 0.0,
             max_block_percentile: 50.0,
-            extra_priority_fee_boost: 0.0,
+            extra_priority_fee_boost: 0.into(),
             ..Default::default()
         };
-        assert_approx_eq!(
+        assert_eq!(
             suggest_priority_fee(
-                &[
+                &rewards(&[
                     1000000000, 1110000000, 1213318421, 1433574636, 1557989644, 1615965689,
                     2000000000, 2557989644, 2910000000, 3000000000
-                ],
+                ]),
                 1.0,
                 &params
             ),
-            1557989644.0
+            U256::from(1557989644u64)
         );
     }
 
@@ -612,19 +1821,19 @@ mod tests {
         let params = Params {
             min_block_percentile: 0.0,
             max_block_percentile: 100.0,
-            extra_priority_fee_boost: 0.0,
+            extra_priority_fee_boost: 0.into(),
             ..Default::default()
         };
-        assert_approx_eq!(
+        assert_eq!(
             suggest_priority_fee(
-                &[
+                &rewards(&[
                     1000000000, 1110000000, 1213318421, 1433574636, 1557989644, 1615965689,
                     2000000000, 2557989644, 2910000000, 3000000000
-                ],
+                ]),
                 1.0,
                 &params
             ),
-            3000000000.0
+            U256::from(3000000000u64)
         );
     }
 }
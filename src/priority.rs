@@ -1,9 +1,12 @@
-use super::{GasPrice1559, GasPriceEstimating};
-use anyhow::{anyhow, Result};
+use super::{Estimate, EstimationRequest, GasPrice, GasPriceEstimating};
+use crate::{Error, Result};
 use std::{
     future::Future,
-    sync::atomic::{AtomicUsize, Ordering},
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 // Errors of an individual estimator are logged as warnings until it has failed this many times in
@@ -12,74 +15,540 @@ use std::{
 // still getting them when the estimator really goes down.
 const LOG_ERROR_AFTER_N_ERRORS: usize = 10;
 
-// Uses the first successful estimator.
+// Uses the first successful estimator. The fee model of the result is the one of the estimator that
+// answered.
 pub struct PriorityGasPriceEstimating {
     estimators: Vec<Estimator>,
+    mode: Mode,
+    circuit_breaker: Option<CircuitBreaker>,
+    // time limit for a single estimator
+    #[cfg(feature = "tokio_")]
+    timeout: Option<Duration>,
+    // time limit for the whole chain
+    #[cfg(feature = "tokio_")]
+    deadline: Option<Duration>,
+}
+
+/// How `PriorityGasPriceEstimating` queries its estimators.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Query the estimators one after another until one succeeds.
+    #[default]
+    Sequential,
+    /// Start the first estimator and then the next one whenever `hedge_delay` has passed without
+    /// an answer or all started estimators have failed. A success is returned as soon as all higher
+    /// priority estimators have failed. At `deadline` the highest priority success so far is
+    /// returned and estimators that are still running count as timed out.
+    #[cfg(feature = "tokio_")]
+    Hedged {
+        hedge_delay: Duration,
+        deadline: Duration,
+    },
+}
+
+/// Skips estimators that keep failing.
+///
+/// After `failure_threshold` errors in a row the estimator is skipped for `cooldown`. Afterwards a
+/// single probe request is let through. The estimator is used normally again if the probe succeeds
+/// and skipped for another `cooldown` if it fails. A probe that does not finish, for example
+/// because the request was cancelled, is replaced by a new one after `cooldown`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    pub failure_threshold: usize,
+    pub cooldown: Duration,
+}
+
+/// Estimator registered under a name and optional labels that identify it in logs, in
+/// `PriorityGasPriceEstimating::status` and as the source of detailed estimates.
+pub struct NamedEstimator {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub estimator: Box<dyn GasPriceEstimating>,
+}
+
+impl NamedEstimator {
+    pub fn new(name: impl Into<String>, estimator: Box<dyn GasPriceEstimating>) -> Self {
+        Self {
+            name: name.into(),
+            labels: Vec::new(),
+            estimator,
+        }
+    }
+
+    pub fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// Health of an estimator in a `PriorityGasPriceEstimating`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EstimatorStatus {
+    // The index for estimators that were not given a name.
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub errors_in_a_row: usize,
+    pub last_success: Option<SystemTime>,
+    pub last_error: Option<String>,
+    pub last_value: Option<GasPrice>,
 }
 
 struct Estimator {
     estimator: Box<dyn GasPriceEstimating>,
+    name: String,
+    labels: Vec<(String, String)>,
+    // whether the name was given explicitly, then it replaces the source of detailed estimates
+    named: bool,
     errors_in_a_row: AtomicUsize,
+    circuit: Mutex<Circuit>,
+    history: Mutex<History>,
+}
+
+#[derive(Default)]
+struct History {
+    last_success: Option<SystemTime>,
+    last_error: Option<String>,
+    last_value: Option<GasPrice>,
+}
+
+// Result of an estimator operation whose gas price is kept in the history and recorded in the
+// metrics.
+pub(crate) trait Answer {
+    fn gas_price(&self) -> GasPrice;
+}
+
+impl Answer for GasPrice {
+    fn gas_price(&self) -> GasPrice {
+        *self
+    }
+}
+
+impl Answer for Estimate {
+    fn gas_price(&self) -> GasPrice {
+        self.gas_price
+    }
+}
+
+#[derive(Default)]
+struct Circuit {
+    // time of the last failure that kept the circuit breaker open
+    opened_at: Option<Instant>,
+    // start of the probe request of the half open circuit breaker
+    probe_started: Option<Instant>,
+}
+
+impl Estimator {
+    fn new(estimator: NamedEstimator, named: bool) -> Self {
+        Self {
+            estimator: estimator.estimator,
+            name: estimator.name,
+            labels: estimator.labels,
+            named,
+            errors_in_a_row: AtomicUsize::new(0),
+            circuit: Default::default(),
+            history: Default::default(),
+        }
+    }
+
+    // Updates the error counter, circuit breaker, history and metrics and logs failures.
+    fn record<R: Answer>(
+        &self,
+        result: &Result<R>,
+        elapsed: Duration,
+        breaker: Option<&CircuitBreaker>,
+    ) {
+        crate::metrics::estimate(
+            &self.name,
+            elapsed,
+            result.as_ref().ok().map(Answer::gas_price).as_ref(),
+        );
+        match result {
+            Ok(answer) => {
+                self.errors_in_a_row.store(0, Ordering::SeqCst);
+                *self.circuit.lock().unwrap() = Circuit::default();
+                let mut history = self.history.lock().unwrap();
+                history.last_success = Some(SystemTime::now());
+                history.last_value = Some(answer.gas_price());
+            }
+            Err(err) => {
+                let num_errors = self.errors_in_a_row.fetch_add(1, Ordering::SeqCst) + 1;
+                let (name, labels) = (&self.name, &self.labels);
+                if num_errors < LOG_ERROR_AFTER_N_ERRORS {
+                    tracing::warn!(estimator = %name, ?labels, ?err, "gas estimator failed");
+                } else {
+                    tracing::error!(estimator = %name, ?labels, ?err, "gas estimator failed");
+                }
+                self.history.lock().unwrap().last_error = Some(err.to_string());
+                if matches!(breaker, Some(breaker) if num_errors >= breaker.failure_threshold) {
+                    *self.circuit.lock().unwrap() = Circuit {
+                        opened_at: Some(Instant::now()),
+                        probe_started: None,
+                    };
+                }
+            }
+        }
+    }
+
+    // Whether the estimator should be queried, claims the probe of a half open circuit breaker.
+    fn is_available(&self, breaker: Option<&CircuitBreaker>) -> bool {
+        let breaker = match breaker {
+            Some(breaker) => breaker,
+            None => return true,
+        };
+        let mut circuit = self.circuit.lock().unwrap();
+        let in_cooldown =
+            |time: Option<Instant>| matches!(time, Some(time) if time.elapsed() < breaker.cooldown);
+        if circuit.opened_at.is_none() {
+            return true;
+        }
+        if in_cooldown(circuit.opened_at) || in_cooldown(circuit.probe_started) {
+            return false;
+        }
+        circuit.probe_started = Some(Instant::now());
+        true
+    }
 }
 
 impl PriorityGasPriceEstimating {
     pub fn new(estimators: Vec<Box<dyn GasPriceEstimating>>) -> Self {
         let estimators = estimators
             .into_iter()
-            .map(|estimator| Estimator {
-                estimator,
-                errors_in_a_row: AtomicUsize::new(0),
+            .enumerate()
+            .map(|(i, estimator)| {
+                Estimator::new(NamedEstimator::new(i.to_string(), estimator), false)
             })
             .collect();
-        Self { estimators }
+        Self::with_estimators(estimators)
     }
 
-    async fn prioritize<'a, T, F>(&'a self, operation: T) -> Result<GasPrice1559>
+    pub fn named(estimators: Vec<NamedEstimator>) -> Self {
+        let estimators = estimators
+            .into_iter()
+            .map(|estimator| Estimator::new(estimator, true))
+            .collect();
+        Self::with_estimators(estimators)
+    }
+
+    fn with_estimators(estimators: Vec<Estimator>) -> Self {
+        Self {
+            estimators,
+            mode: Mode::default(),
+            circuit_breaker: None,
+            #[cfg(feature = "tokio_")]
+            timeout: None,
+            #[cfg(feature = "tokio_")]
+            deadline: None,
+        }
+    }
+
+    /// The status of the estimators in priority order.
+    pub fn status(&self) -> Vec<EstimatorStatus> {
+        self.estimators
+            .iter()
+            .map(|estimator| {
+                let history = estimator.history.lock().unwrap();
+                EstimatorStatus {
+                    name: estimator.name.clone(),
+                    labels: estimator.labels.clone(),
+                    errors_in_a_row: estimator.errors_in_a_row.load(Ordering::SeqCst),
+                    last_success: history.last_success,
+                    last_error: history.last_error.clone(),
+                    last_value: history.last_value,
+                }
+            })
+            .collect()
+    }
+
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Limits the time a single estimator gets to answer. An estimator that does not answer in time
+    /// fails with `Error::Timeout`.
+    #[cfg(feature = "tokio_")]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the time of a whole estimate. Estimators that have not been queried by then are
+    /// skipped, and the ones still running do not count as failed for the circuit breaker. In
+    /// hedged mode the earlier of this and the mode's deadline applies.
+    #[cfg(feature = "tokio_")]
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    // Runs an estimator operation within the timeout and what is left of the deadline of an
+    // estimate that began at `start`. None if the deadline passed first, which is not a failure of
+    // the estimator unlike exceeding the timeout.
+    #[cfg(feature = "tokio_")]
+    async fn bounded<R>(
+        &self,
+        operation: impl Future<Output = Result<R>>,
+        start: Instant,
+    ) -> Option<Result<R>> {
+        let remaining = self
+            .deadline
+            .map(|deadline| deadline.saturating_sub(start.elapsed()));
+        let limit = match (self.timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some((timeout.min(remaining), remaining < timeout)),
+            (Some(timeout), None) => Some((timeout, false)),
+            (None, Some(remaining)) => Some((remaining, true)),
+            (None, None) => None,
+        };
+        match limit {
+            Some((limit, is_deadline)) => match tokio::time::timeout(limit, operation).await {
+                Ok(result) => Some(result),
+                Err(_) if is_deadline => None,
+                Err(_) => Some(Err(Error::Timeout)),
+            },
+            None => Some(operation.await),
+        }
+    }
+
+    #[cfg(not(feature = "tokio_"))]
+    async fn bounded<R>(
+        &self,
+        operation: impl Future<Output = Result<R>>,
+        _: Instant,
+    ) -> Option<Result<R>> {
+        Some(operation.await)
+    }
+
+    #[cfg(feature = "tokio_")]
+    fn deadline_passed(&self, start: Instant) -> bool {
+        matches!(self.deadline, Some(deadline) if start.elapsed() >= deadline)
+    }
+
+    #[cfg(not(feature = "tokio_"))]
+    fn deadline_passed(&self, _: Instant) -> bool {
+        false
+    }
+
+    async fn prioritize<'a, T, F, R>(&'a self, operation: T) -> Result<R>
+    where
+        T: Fn(&'a Estimator) -> F,
+        F: Future<Output = Result<R>>,
+        R: Answer,
+    {
+        match self.mode {
+            Mode::Sequential => self.sequential(operation).await,
+            #[cfg(feature = "tokio_")]
+            Mode::Hedged {
+                hedge_delay,
+                deadline,
+            } => self.hedged(operation, hedge_delay, deadline).await,
+        }
+    }
+
+    async fn sequential<'a, T, F, R>(&'a self, operation: T) -> Result<R>
     where
-        T: Fn(&'a dyn GasPriceEstimating) -> F,
-        F: Future<Output = Result<GasPrice1559>>,
+        T: Fn(&'a Estimator) -> F,
+        F: Future<Output = Result<R>>,
+        R: Answer,
     {
-        for (i, estimator) in self.estimators.iter().enumerate() {
-            match operation(estimator.estimator.as_ref()).await {
-                Ok(result) => {
-                    estimator.errors_in_a_row.store(0, Ordering::SeqCst);
-                    return Ok(result);
+        let start = Instant::now();
+        let mut errors = Vec::new();
+        let breaker = self.circuit_breaker.as_ref();
+        for estimator in &self.estimators {
+            if self.deadline_passed(start) {
+                tracing::debug!(
+                    estimator = %estimator.name,
+                    "deadline passed before gas estimator"
+                );
+                errors.push(Error::Timeout);
+                break;
+            }
+            if !estimator.is_available(breaker) {
+                tracing::debug!(
+                    estimator = %estimator.name,
+                    "skipping gas estimator with open circuit breaker"
+                );
+                errors.push(Error::CircuitOpen);
+                continue;
+            }
+            let estimator_start = Instant::now();
+            let Some(result) = self.bounded(operation(estimator), start).await else {
+                tracing::debug!(
+                    estimator = %estimator.name,
+                    "deadline passed during gas estimator"
+                );
+                errors.push(Error::Timeout);
+                continue;
+            };
+            estimator.record(&result, estimator_start.elapsed(), breaker);
+            match result {
+                Ok(result) => return Ok(result),
+                Err(err) => errors.push(err),
+            }
+        }
+        Err(Error::AllFailed(errors))
+    }
+
+    #[cfg(feature = "tokio_")]
+    async fn hedged<'a, T, F, R>(
+        &'a self,
+        operation: T,
+        hedge_delay: Duration,
+        deadline: Duration,
+    ) -> Result<R>
+    where
+        T: Fn(&'a Estimator) -> F,
+        F: Future<Output = Result<R>>,
+        R: Answer,
+    {
+        use futures::{
+            future::FutureExt,
+            stream::{FuturesUnordered, StreamExt},
+        };
+        use tokio::time::sleep;
+
+        let breaker = self.circuit_breaker.as_ref();
+        let now = Instant::now();
+        let start = |i: usize| {
+            self.bounded(operation(&self.estimators[i]), now)
+                .map(move |result| (i, result))
+        };
+        // None while the estimator has not been started or not answered yet.
+        let mut results: Vec<Option<Result<R>>> = self.estimators.iter().map(|_| None).collect();
+        let mut started_at = vec![now; self.estimators.len()];
+        let mut running = FuturesUnordered::new();
+        let mut started = 0;
+        let mut start_next = true;
+        let hedge = sleep(hedge_delay);
+        // Estimators still running when the deadline of the mode passes count as timed out, but not
+        // if the overall deadline of the estimate passes first.
+        let overall_deadline = matches!(self.deadline, Some(d) if d < deadline);
+        let deadline = sleep(self.deadline.map_or(deadline, |d| d.min(deadline)));
+        tokio::pin!(hedge, deadline);
+        loop {
+            // Once an estimator succeeded only the higher priority ones still running can change
+            // the result, so no further estimators are started.
+            let succeeded = results.iter().any(|result| matches!(result, Some(Ok(_))));
+            if !succeeded && (start_next || running.is_empty()) {
+                while started < self.estimators.len() {
+                    let i = started;
+                    started += 1;
+                    if self.estimators[i].is_available(breaker) {
+                        started_at[i] = Instant::now();
+                        running.push(start(i));
+                        hedge
+                            .as_mut()
+                            .reset(tokio::time::Instant::now() + hedge_delay);
+                        break;
+                    }
+                    tracing::debug!(
+                        estimator = %self.estimators[i].name,
+                        "skipping gas estimator with open circuit breaker"
+                    );
+                    results[i] = Some(Err(Error::CircuitOpen));
                 }
-                Err(err) => {
-                    let num_errors = estimator.errors_in_a_row.fetch_add(1, Ordering::SeqCst) + 1;
-                    if num_errors < LOG_ERROR_AFTER_N_ERRORS {
-                        tracing::warn!("gas estimator {} failed: {:?}", i, err);
-                    } else {
-                        tracing::error!("gas estimator {} failed: {:?}", i, err);
+                start_next = false;
+            }
+            // The highest priority estimator that has not failed decides.
+            match results
+                .iter()
+                .position(|result| !matches!(result, Some(Err(_))))
+            {
+                Some(i) if matches!(results[i], Some(Ok(_))) => return results[i].take().unwrap(),
+                None => {
+                    return Err(Error::AllFailed(
+                        results
+                            .into_iter()
+                            .flatten()
+                            .filter_map(Result::err)
+                            .collect(),
+                    ))
+                }
+                _ => (),
+            }
+            tokio::select! {
+                Some((i, result)) = running.next() => {
+                    match &result {
+                        Some(result) => {
+                            self.estimators[i].record(result, started_at[i].elapsed(), breaker)
+                        }
+                        None => tracing::debug!(
+                            estimator = %self.estimators[i].name,
+                            "deadline passed during gas estimator"
+                        ),
                     }
+                    results[i] = Some(result.unwrap_or(Err(Error::Timeout)));
+                }
+                _ = &mut hedge, if !succeeded && started < self.estimators.len() => {
+                    start_next = true
+                }
+                _ = &mut deadline => break,
+            }
+        }
+
+        let mut errors = Vec::new();
+        for (i, result) in results.into_iter().enumerate().take(started) {
+            match result {
+                Some(Ok(result)) => return Ok(result),
+                Some(Err(err)) => errors.push(err),
+                None => {
+                    if !overall_deadline {
+                        self.estimators[i].record::<R>(
+                            &Err(Error::Timeout),
+                            started_at[i].elapsed(),
+                            breaker,
+                        );
+                    }
+                    errors.push(Error::Timeout);
                 }
             }
         }
-        Err(anyhow!("all gas estimators failed"))
+        Err(Error::AllFailed(errors))
     }
 }
 
 #[async_trait::async_trait]
 impl GasPriceEstimating for PriorityGasPriceEstimating {
-    async fn estimate_with_limits(
-        &self,
-        gas_limit: f64,
-        time_limit: Duration,
-    ) -> Result<GasPrice1559> {
-        self.prioritize(|estimator| estimator.estimate_with_limits(gas_limit, time_limit))
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
+        self.prioritize(|estimator| {
+            estimator
+                .estimator
+                .estimate_with_limits(gas_limit, time_limit)
+        })
+        .await
+    }
+
+    async fn estimate(&self) -> Result<GasPrice> {
+        self.prioritize(|estimator| estimator.estimator.estimate())
+            .await
+    }
+
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        self.prioritize(|estimator| estimator.estimator.estimate_with_request(request))
             .await
     }
 
-    async fn estimate(&self) -> Result<GasPrice1559> {
-        self.prioritize(|estimator| estimator.estimate()).await
+    // The estimate of the estimator that answered with its name as source if it has one.
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        self.prioritize(|estimator| async move {
+            let mut estimate = estimator.estimator.estimate_detailed(request).await?;
+            if estimator.named {
+                estimate.source = estimator.name.clone();
+            }
+            Ok(estimate)
+        })
+        .await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::MockGasPriceEstimating;
+    use super::super::{GasPrice1559, MockGasPriceEstimating};
     use super::*;
-    use assert_approx_eq::assert_approx_eq;
     use futures::future::FutureExt;
 
     #[test]
@@ -89,9 +558,10 @@ mod tests {
 
         estimator_0.expect_estimate().times(1).returning(|| {
             Ok(GasPrice1559 {
-                base_fee_per_gas: 1.0,
+                base_fee_per_gas: 1.into(),
                 ..Default::default()
-            })
+            }
+            .into())
         });
 
         let priority =
@@ -108,18 +578,120 @@ mod tests {
         estimator_0
             .expect_estimate()
             .times(1)
-            .returning(|| Err(anyhow!("")));
+            .returning(|| Err(Error::NoData));
         estimator_1.expect_estimate().times(1).returning(|| {
             Ok(GasPrice1559 {
-                base_fee_per_gas: 2.0,
+                base_fee_per_gas: 2.into(),
                 ..Default::default()
-            })
+            }
+            .into())
         });
 
         let priority =
             PriorityGasPriceEstimating::new(vec![Box::new(estimator_0), Box::new(estimator_1)]);
         let result = priority.estimate().now_or_never().unwrap().unwrap();
-        assert_approx_eq!(result.base_fee_per_gas, 2.0);
+        assert_eq!(result.to_eip1559().base_fee_per_gas, 2.into());
+    }
+
+    #[test]
+    fn prioritize_forwards_request() {
+        let mut estimator_0 = MockGasPriceEstimating::new();
+        let request = EstimationRequest::default().with_target_block(42);
+
+        estimator_0
+            .expect_estimate_with_request()
+            .withf(move |r| *r == request)
+            .times(1)
+            .returning(|_| Ok(Default::default()));
+
+        let priority = PriorityGasPriceEstimating::new(vec![Box::new(estimator_0)]);
+        priority
+            .estimate_with_request(request)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn prioritize_returns_estimate_of_answering_estimator() {
+        let mut estimator_0 = MockGasPriceEstimating::new();
+        let mut estimator_1 = MockGasPriceEstimating::new();
+
+        estimator_0
+            .expect_estimate_detailed()
+            .times(1)
+            .returning(|_| Err(Error::NoData));
+        estimator_1
+            .expect_estimate_detailed()
+            .times(1)
+            .returning(|_| Ok(Estimate::new(Default::default(), "second").with_block_number(1)));
+
+        let priority =
+            PriorityGasPriceEstimating::new(vec![Box::new(estimator_0), Box::new(estimator_1)]);
+        let estimate = priority
+            .estimate_detailed(Default::default())
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(estimate.source, "second");
+        assert_eq!(estimate.block_number, Some(1));
+    }
+
+    #[test]
+    fn named_estimator_is_source_of_estimate() {
+        let mut estimator = MockGasPriceEstimating::new();
+        estimator
+            .expect_estimate_detailed()
+            .returning(|_| Ok(Estimate::new(Default::default(), "inner")));
+
+        let priority = PriorityGasPriceEstimating::named(vec![NamedEstimator::new(
+            "primary",
+            Box::new(estimator),
+        )]);
+        let estimate = priority
+            .estimate_detailed(Default::default())
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(estimate.source, "primary");
+    }
+
+    #[test]
+    fn status_reports_history() {
+        let gas_price = GasPrice::Legacy {
+            gas_price: 3.into(),
+        };
+        let mut estimator_0 = MockGasPriceEstimating::new();
+        let mut estimator_1 = MockGasPriceEstimating::new();
+        estimator_0
+            .expect_estimate()
+            .returning(|| Err(Error::StaleCache));
+        estimator_1
+            .expect_estimate()
+            .returning(move || Ok(gas_price));
+
+        let priority = PriorityGasPriceEstimating::named(vec![
+            NamedEstimator::new("a", Box::new(estimator_0)).with_label("kind", "api"),
+            NamedEstimator::new("b", Box::new(estimator_1)),
+        ]);
+        priority.estimate().now_or_never().unwrap().unwrap();
+        priority.estimate().now_or_never().unwrap().unwrap();
+
+        let status = priority.status();
+        assert_eq!(status[0].name, "a");
+        assert_eq!(
+            status[0].labels,
+            vec![("kind".to_string(), "api".to_string())]
+        );
+        assert_eq!(status[0].errors_in_a_row, 2);
+        assert_eq!(
+            status[0].last_error.as_deref(),
+            Some("cached response is stale")
+        );
+        assert_eq!(status[0].last_success, None);
+        assert_eq!(status[1].errors_in_a_row, 0);
+        assert!(status[1].last_success.is_some());
+        assert_eq!(status[1].last_value, Some(gas_price));
     }
 
     #[test]
@@ -130,15 +702,269 @@ mod tests {
         estimator_0
             .expect_estimate()
             .times(1)
-            .returning(|| Err(anyhow!("")));
+            .returning(|| Err(Error::NoData));
         estimator_1
             .expect_estimate()
             .times(1)
-            .returning(|| Err(anyhow!("")));
+            .returning(|| Err(Error::StaleCache));
 
         let priority =
             PriorityGasPriceEstimating::new(vec![Box::new(estimator_0), Box::new(estimator_1)]);
         let result = priority.estimate().now_or_never().unwrap();
-        assert!(result.is_err());
+        assert!(matches!(
+            result,
+            Err(Error::AllFailed(errors))
+                if matches!(errors[..], [Error::NoData, Error::StaleCache])
+        ));
+    }
+
+    fn failing_then_succeeding(failures: usize) -> MockGasPriceEstimating {
+        let mut estimator = MockGasPriceEstimating::new();
+        let mut calls = 0;
+        estimator.expect_estimate().returning(move || {
+            calls += 1;
+            if calls <= failures {
+                Err(Error::NoData)
+            } else {
+                Ok(Default::default())
+            }
+        });
+        estimator
+    }
+
+    #[test]
+    fn circuit_breaker_skips_failing_estimator() {
+        let mut estimator_0 = MockGasPriceEstimating::new();
+        let mut estimator_1 = MockGasPriceEstimating::new();
+
+        estimator_0
+            .expect_estimate()
+            .times(2)
+            .returning(|| Err(Error::NoData));
+        estimator_1
+            .expect_estimate()
+            .times(3)
+            .returning(|| Ok(Default::default()));
+
+        let priority =
+            PriorityGasPriceEstimating::new(vec![Box::new(estimator_0), Box::new(estimator_1)])
+                .with_circuit_breaker(CircuitBreaker {
+                    failure_threshold: 2,
+                    cooldown: Duration::from_secs(3600),
+                });
+        for _ in 0..3 {
+            priority.estimate().now_or_never().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn circuit_breaker_probes_after_cooldown() {
+        let priority = PriorityGasPriceEstimating::new(vec![Box::new(failing_then_succeeding(2))])
+            .with_circuit_breaker(CircuitBreaker {
+                failure_threshold: 1,
+                cooldown: Duration::from_millis(20),
+            });
+        assert!(priority.estimate().now_or_never().unwrap().is_err());
+        assert!(matches!(
+            priority.estimate().now_or_never().unwrap(),
+            Err(Error::AllFailed(errors)) if matches!(errors[..], [Error::CircuitOpen])
+        ));
+
+        // The failed probe opens the circuit breaker again.
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(matches!(
+            priority.estimate().now_or_never().unwrap(),
+            Err(Error::AllFailed(errors)) if matches!(errors[..], [Error::NoData])
+        ));
+        assert!(matches!(
+            priority.estimate().now_or_never().unwrap(),
+            Err(Error::AllFailed(errors)) if matches!(errors[..], [Error::CircuitOpen])
+        ));
+
+        // The successful probe closes it.
+        std::thread::sleep(Duration::from_millis(30));
+        priority.estimate().now_or_never().unwrap().unwrap();
+        priority.estimate().now_or_never().unwrap().unwrap();
+    }
+
+    #[cfg(feature = "tokio_")]
+    mod timing {
+        use super::*;
+
+        // Answers with a legacy gas price after `delay` or fails if there is none.
+        struct Delayed {
+            delay: Duration,
+            gas_price: Option<u64>,
+        }
+
+        #[async_trait::async_trait]
+        impl GasPriceEstimating for Delayed {
+            async fn estimate_with_limits(&self, _: f64, _: Duration) -> Result<GasPrice> {
+                tokio::time::sleep(self.delay).await;
+                self.gas_price.map(legacy).ok_or(Error::NoData)
+            }
+        }
+
+        fn delayed(millis: u64, gas_price: Option<u64>) -> Box<dyn GasPriceEstimating> {
+            Box::new(Delayed {
+                delay: Duration::from_millis(millis),
+                gas_price,
+            })
+        }
+
+        fn legacy(gas_price: u64) -> GasPrice {
+            GasPrice::Legacy {
+                gas_price: gas_price.into(),
+            }
+        }
+
+        fn hedged(hedge_delay: u64, deadline: u64) -> Mode {
+            Mode::Hedged {
+                hedge_delay: Duration::from_millis(hedge_delay),
+                deadline: Duration::from_millis(deadline),
+            }
+        }
+
+        #[tokio::test]
+        async fn hedged_uses_fallback_if_primary_hangs() {
+            let priority = PriorityGasPriceEstimating::new(vec![
+                delayed(60_000, Some(1)),
+                delayed(0, Some(2)),
+            ])
+            .with_mode(hedged(10, 200));
+            let start = std::time::Instant::now();
+            assert_eq!(priority.estimate().await.unwrap(), legacy(2));
+            assert!(start.elapsed() < Duration::from_secs(10));
+            assert_eq!(
+                priority.estimators[0]
+                    .errors_in_a_row
+                    .load(Ordering::SeqCst),
+                1
+            );
+        }
+
+        #[tokio::test]
+        async fn hedged_stops_starting_estimators_after_success() {
+            // fails the test if it is called
+            let unused = Box::new(MockGasPriceEstimating::new());
+            let priority = PriorityGasPriceEstimating::new(vec![
+                delayed(100, Some(1)),
+                delayed(0, Some(2)),
+                unused,
+            ])
+            .with_mode(hedged(10, 5_000));
+            assert_eq!(priority.estimate().await.unwrap(), legacy(1));
+        }
+
+        #[tokio::test]
+        async fn hedged_prefers_primary_within_deadline() {
+            let priority =
+                PriorityGasPriceEstimating::new(vec![delayed(50, Some(1)), delayed(0, Some(2))])
+                    .with_mode(hedged(10, 5_000));
+            assert_eq!(priority.estimate().await.unwrap(), legacy(1));
+        }
+
+        #[tokio::test]
+        async fn hedged_starts_next_when_primary_fails() {
+            let priority =
+                PriorityGasPriceEstimating::new(vec![delayed(0, None), delayed(0, Some(2))])
+                    .with_mode(hedged(60_000, 120_000));
+            assert_eq!(priority.estimate().await.unwrap(), legacy(2));
+        }
+
+        #[tokio::test]
+        async fn hedged_skips_estimators_with_open_circuit_breaker() {
+            let priority =
+                PriorityGasPriceEstimating::new(vec![delayed(0, None), delayed(0, Some(2))])
+                    .with_mode(hedged(60_000, 120_000))
+                    .with_circuit_breaker(CircuitBreaker {
+                        failure_threshold: 1,
+                        cooldown: Duration::from_secs(3600),
+                    });
+            assert_eq!(priority.estimate().await.unwrap(), legacy(2));
+            assert_eq!(priority.estimate().await.unwrap(), legacy(2));
+            assert_eq!(
+                priority.estimators[0]
+                    .errors_in_a_row
+                    .load(Ordering::SeqCst),
+                1
+            );
+        }
+
+        #[tokio::test]
+        async fn hedged_fails_if_all_fail_or_time_out() {
+            let priority =
+                PriorityGasPriceEstimating::new(vec![delayed(0, None), delayed(60_000, Some(2))])
+                    .with_mode(hedged(10, 50));
+            assert!(matches!(
+                priority.estimate().await,
+                Err(Error::AllFailed(errors))
+                    if matches!(errors[..], [Error::NoData, Error::Timeout])
+            ));
+        }
+
+        #[tokio::test]
+        async fn timed_out_estimator_counts_as_failure() {
+            let priority = PriorityGasPriceEstimating::new(vec![
+                delayed(60_000, Some(1)),
+                delayed(0, Some(2)),
+            ])
+            .with_timeout(Duration::from_millis(20));
+            assert_eq!(priority.estimate().await.unwrap(), legacy(2));
+            assert_eq!(
+                priority.estimators[0]
+                    .errors_in_a_row
+                    .load(Ordering::SeqCst),
+                1
+            );
+        }
+
+        #[tokio::test]
+        async fn deadline_stops_the_chain() {
+            let priority = PriorityGasPriceEstimating::new(vec![
+                delayed(60_000, Some(1)),
+                delayed(0, Some(2)),
+            ])
+            .with_timeout(Duration::from_secs(60))
+            .with_deadline(Duration::from_millis(20));
+            assert!(matches!(
+                priority.estimate().await,
+                Err(Error::AllFailed(errors))
+                    if matches!(errors[..], [Error::Timeout, Error::Timeout])
+            ));
+            assert_eq!(
+                priority.estimators[1]
+                    .errors_in_a_row
+                    .load(Ordering::SeqCst),
+                0
+            );
+        }
+
+        #[tokio::test]
+        async fn deadline_is_no_estimator_failure() {
+            let breaker = CircuitBreaker {
+                failure_threshold: 1,
+                cooldown: Duration::from_secs(60),
+            };
+            for mode in [Mode::Sequential, hedged(60_000, 60_000)] {
+                let priority = PriorityGasPriceEstimating::new(vec![delayed(60_000, Some(1))])
+                    .with_mode(mode)
+                    .with_circuit_breaker(breaker)
+                    .with_timeout(Duration::from_secs(60))
+                    .with_deadline(Duration::from_millis(20));
+                for _ in 0..2 {
+                    assert!(matches!(
+                        priority.estimate().await,
+                        Err(Error::AllFailed(errors)) if matches!(errors[..], [Error::Timeout])
+                    ));
+                }
+                assert_eq!(
+                    priority.estimators[0]
+                        .errors_in_a_row
+                        .load(Ordering::SeqCst),
+                    0
+                );
+            }
+        }
     }
 }
@@ -0,0 +1,218 @@
+use crate::{Error, Result};
+use crate::{GasPrice, ReplacementRules, DEFAULT_GAS_LIMIT, DEFAULT_TIME_LIMIT};
+use primitive_types::U256;
+use std::time::Duration;
+
+/// Parameters of a gas price estimation.
+///
+/// Built with `EstimationRequest::default()` followed by the `with_*` methods. Estimators honour the
+/// fields they can and ignore the rest, see `GasPriceEstimating::estimate_with_request`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EstimationRequest {
+    // Gas used by the transaction.
+    pub gas_limit: f64,
+    // Time within which the transaction should be mined.
+    pub time_limit: Duration,
+    // Desired probability in (0, 1] of the transaction being mined in the next block.
+    pub confidence: Option<f64>,
+    // Maximum fee per gas the caller is willing to pay.
+    pub max_fee: Option<U256>,
+    // Block number by which the transaction should be mined, takes precedence over time_limit.
+    pub target_block: Option<u64>,
+    // Gas price of the pending transaction that the transaction replaces.
+    pub replacement: Option<GasPrice>,
+    // Fee bumps the node requires for a replacement.
+    pub replacement_rules: ReplacementRules,
+    // Whether the transaction carries blobs and needs a max fee per blob gas. If not set the estimate
+    // keeps the max fee per blob gas if the estimator provides one.
+    pub blobs: Option<bool>,
+}
+
+impl Default for EstimationRequest {
+    fn default() -> Self {
+        Self {
+            gas_limit: DEFAULT_GAS_LIMIT,
+            time_limit: DEFAULT_TIME_LIMIT,
+            confidence: None,
+            max_fee: None,
+            target_block: None,
+            replacement: None,
+            replacement_rules: Default::default(),
+            blobs: None,
+        }
+    }
+}
+
+impl EstimationRequest {
+    pub fn with_gas_limit(self, gas_limit: f64) -> Self {
+        Self { gas_limit, ..self }
+    }
+
+    pub fn with_time_limit(self, time_limit: Duration) -> Self {
+        Self { time_limit, ..self }
+    }
+
+    pub fn with_confidence(self, confidence: f64) -> Self {
+        Self {
+            confidence: Some(confidence),
+            ..self
+        }
+    }
+
+    pub fn with_max_fee(self, max_fee: U256) -> Self {
+        Self {
+            max_fee: Some(max_fee),
+            ..self
+        }
+    }
+
+    pub fn with_target_block(self, target_block: u64) -> Self {
+        Self {
+            target_block: Some(target_block),
+            ..self
+        }
+    }
+
+    pub fn with_replacement(self, previous: GasPrice) -> Self {
+        Self {
+            replacement: Some(previous),
+            ..self
+        }
+    }
+
+    pub fn with_replacement_rules(self, replacement_rules: ReplacementRules) -> Self {
+        Self {
+            replacement_rules,
+            ..self
+        }
+    }
+
+    pub fn with_blobs(self, blobs: bool) -> Self {
+        Self {
+            blobs: Some(blobs),
+            ..self
+        }
+    }
+
+    /// Applies the estimator independent parts of the request to an estimate:
+    /// - the fee is capped at `max_fee`, failing if the cap leaves the price invalid
+    /// - blob transactions require a max fee per blob gas, transactions known to carry no blobs drop
+    ///   it
+    /// - replacements are bumped over the replaced price by the replacement rules, failing if
+    ///   `max_fee` doesn't allow the bump
+    pub fn apply(&self, gas_price: GasPrice) -> Result<GasPrice> {
+        let gas_price = match (self.blobs, gas_price.max_fee_per_blob_gas()) {
+            (Some(true), None) => {
+                return Err(Error::validation(
+                    "estimate has no max fee per blob gas for blob transaction",
+                ))
+            }
+            (Some(false), Some(_)) => gas_price.to_eip1559().into(),
+            _ => gas_price,
+        };
+        let gas_price = match self.replacement {
+            Some(previous) => {
+                let replacement =
+                    GasPrice::replacement_for(previous, gas_price, &self.replacement_rules);
+                let max_fee = replacement.to_eip1559().max_fee_per_gas;
+                if self.max_fee.is_some_and(|cap| cap < max_fee) {
+                    return Err(Error::Validation(format!(
+                        "replacement needs max fee {} above the cap",
+                        max_fee
+                    )));
+                }
+                replacement
+            }
+            None => gas_price,
+        };
+        match self.max_fee {
+            Some(max_fee) => gas_price.limit_cap(max_fee).validate(),
+            None => Ok(gas_price),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FeeModel, GasPrice1559};
+
+    fn gas_price() -> GasPrice {
+        GasPrice::from(GasPrice1559 {
+            base_fee_per_gas: 10.into(),
+            max_fee_per_gas: 30.into(),
+            max_priority_fee_per_gas: 2.into(),
+        })
+    }
+
+    #[test]
+    fn apply_default_request_keeps_estimate() {
+        let request = EstimationRequest::default();
+        assert_eq!(request.apply(gas_price()).unwrap(), gas_price());
+    }
+
+    #[test]
+    fn apply_max_fee() {
+        let request = EstimationRequest::default().with_max_fee(20.into());
+        assert_eq!(
+            request
+                .apply(gas_price())
+                .unwrap()
+                .to_eip1559()
+                .max_fee_per_gas,
+            20.into()
+        );
+
+        let request = EstimationRequest::default().with_max_fee(5.into());
+        assert!(request.apply(gas_price()).is_err());
+    }
+
+    #[test]
+    fn apply_replacement() {
+        let previous = GasPrice::from(GasPrice1559 {
+            base_fee_per_gas: 10.into(),
+            max_fee_per_gas: 40.into(),
+            max_priority_fee_per_gas: 2.into(),
+        });
+        let request = EstimationRequest::default().with_replacement(previous);
+        assert_eq!(
+            request.apply(gas_price()).unwrap(),
+            GasPrice::from(GasPrice1559 {
+                base_fee_per_gas: 10.into(),
+                max_fee_per_gas: 44.into(),
+                max_priority_fee_per_gas: 3.into(),
+            })
+        );
+
+        let request =
+            request.with_replacement_rules(ReplacementRules::default().with_min_bump_percent(50));
+        assert_eq!(
+            request
+                .apply(gas_price())
+                .unwrap()
+                .to_eip1559()
+                .max_fee_per_gas,
+            60.into()
+        );
+        assert!(request.with_max_fee(50.into()).apply(gas_price()).is_err());
+    }
+
+    #[test]
+    fn apply_blobs() {
+        let blob_price = gas_price().with_blob_fee(1.into());
+
+        let request = EstimationRequest::default().with_blobs(true);
+        assert!(request.apply(gas_price()).is_err());
+        assert_eq!(request.apply(blob_price).unwrap(), blob_price);
+
+        let request = EstimationRequest::default();
+        assert_eq!(request.apply(blob_price).unwrap(), blob_price);
+        assert_eq!(request.apply(gas_price()).unwrap(), gas_price());
+
+        let request = EstimationRequest::default().with_blobs(false);
+        assert_eq!(
+            request.apply(blob_price).unwrap().fee_model(),
+            FeeModel::Eip1559
+        );
+    }
+}
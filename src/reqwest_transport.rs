@@ -0,0 +1,151 @@
+use super::{Error, Result, Transport};
+use http::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+const DEFAULT_USER_AGENT: &str = concat!("gas-estimation/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // time limit for a whole request including reading the response body
+    pub timeout: Option<Duration>,
+    // time limit for establishing a connection
+    pub connect_timeout: Option<Duration>,
+    // value of the User-Agent header
+    pub user_agent: String,
+    // headers sent with every request, the headers passed to `get_json` take precedence
+    pub default_headers: HeaderMap,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: Some(Duration::from_secs(10)),
+            connect_timeout: Some(Duration::from_secs(5)),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            default_headers: HeaderMap::new(),
+        }
+    }
+}
+
+/// `Transport` implementation based on a `reqwest::Client`.
+///
+/// Responses with a non success status code result in `Error::Status`, timeouts in
+/// `Error::Timeout` and bodies that are not the expected json in `Error::Parse`.
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(config: Config) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(config.user_agent)
+            .default_headers(config.default_headers);
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        let client = builder.build().map_err(Error::transport)?;
+        Ok(Self { client })
+    }
+
+    /// Uses an already configured client as is.
+    pub fn with_client(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for ReqwestTransport {
+    async fn get_json<T: DeserializeOwned>(&self, url: &str, header: HeaderMap) -> Result<T> {
+        let response = self
+            .client
+            .get(url)
+            .headers(header)
+            .send()
+            .await
+            .map_err(request_error)?;
+        check_status(response.status(), response.headers())?;
+        let body = response.bytes().await.map_err(request_error)?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+fn request_error(err: reqwest::Error) -> Error {
+    if err.is_timeout() {
+        Error::Timeout
+    } else {
+        Error::transport(err)
+    }
+}
+
+fn check_status(status: http::StatusCode, headers: &HeaderMap) -> Result<()> {
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(Error::Status {
+        status,
+        retry_after: headers.get(RETRY_AFTER).and_then(retry_after),
+    })
+}
+
+// Only the delay-seconds form of the header is supported.
+fn retry_after(value: &HeaderValue) -> Option<Duration> {
+    let seconds = value.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    #[test]
+    fn success_status_passes() {
+        assert!(check_status(StatusCode::OK, &HeaderMap::new()).is_ok());
+    }
+
+    #[test]
+    fn error_status_includes_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3"));
+        assert!(matches!(
+            check_status(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Err(Error::Status {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                retry_after: Some(delay),
+            }) if delay == Duration::from_secs(3)
+        ));
+        assert!(matches!(
+            check_status(StatusCode::BAD_GATEWAY, &HeaderMap::new()),
+            Err(Error::Status {
+                status: StatusCode::BAD_GATEWAY,
+                retry_after: None,
+            })
+        ));
+    }
+
+    #[test]
+    fn retry_after_ignores_dates() {
+        assert_eq!(
+            retry_after(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")),
+            None
+        );
+        assert_eq!(
+            retry_after(&HeaderValue::from_static(" 120 ")),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn builds_client() {
+        let mut config = Config::default();
+        config
+            .default_headers
+            .insert("x-api-key", HeaderValue::from_static("key"));
+        assert!(ReqwestTransport::new(config).is_ok());
+    }
+}
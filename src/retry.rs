@@ -0,0 +1,254 @@
+use super::{Error, Result, Transport};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // total number of requests made before giving up, including the first one
+    pub attempts: usize,
+    // delay before the first retry
+    pub initial_backoff: Duration,
+    // factor by which the delay grows with every retry
+    pub multiplier: f64,
+    // upper bound for the delay between two attempts, also for delays requested through
+    // `Retry-After`
+    pub max_backoff: Duration,
+    // fraction in [0, 1] by which a delay is randomly shortened to spread out retries
+    pub jitter: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Config {
+    // Delay before retry number `retry` (starting at 0) without jitter.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry as i32);
+        if backoff.is_finite() && backoff < self.max_backoff.as_secs_f64() {
+            Duration::from_secs_f64(backoff)
+        } else {
+            self.max_backoff
+        }
+    }
+}
+
+/// Retries failed requests of the inner transport with exponential backoff and jitter.
+///
+/// Only transient errors are retried: transport failures, timeouts and the status codes 408, 429
+/// and 5xx. A delay requested through `Retry-After` is used instead of the backoff unless it
+/// exceeds `max_backoff` in which case the error is returned immediately.
+pub struct RetryingTransport<T> {
+    inner: T,
+    config: Config,
+}
+
+impl<T: Transport> RetryingTransport<T> {
+    pub fn new(inner: T, config: Config) -> Self {
+        Self { inner, config }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Transport> Transport for RetryingTransport<T> {
+    async fn get_json<U: DeserializeOwned>(
+        &self,
+        url: &str,
+        header: http::header::HeaderMap,
+    ) -> Result<U> {
+        let mut retry = 0;
+        loop {
+            let err = match self.inner.get_json(url, header.clone()).await {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+            if retry + 1 >= self.config.attempts.max(1) || !is_transient(&err) {
+                return Err(err);
+            }
+            let delay = match retry_after(&err) {
+                Some(delay) if delay > self.config.max_backoff => return Err(err),
+                Some(delay) => delay,
+                None => {
+                    let jitter = self.config.jitter.clamp(0.0, 1.0);
+                    self.config
+                        .backoff(retry as u32)
+                        .mul_f64(1.0 - rand::thread_rng().gen_range(0.0..=jitter))
+                }
+            };
+            tracing::debug!(?err, ?delay, retry, "retrying failed request");
+            tokio::time::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::Transport(_) | Error::Timeout => true,
+        Error::Status { status, .. } => {
+            status.is_server_error()
+                || *status == http::StatusCode::TOO_MANY_REQUESTS
+                || *status == http::StatusCode::REQUEST_TIMEOUT
+        }
+        _ => false,
+    }
+}
+
+fn retry_after(err: &Error) -> Option<Duration> {
+    match err {
+        Error::Status { retry_after, .. } => *retry_after,
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+    use std::{collections::VecDeque, sync::Mutex};
+
+    // Returns the queued results in order and counts the requests.
+    #[derive(Default)]
+    struct QueueTransport {
+        results: Mutex<VecDeque<Result<serde_json::Value>>>,
+    }
+
+    impl QueueTransport {
+        fn new(results: Vec<Result<serde_json::Value>>) -> Self {
+            Self {
+                results: Mutex::new(results.into()),
+            }
+        }
+
+        fn remaining(&self) -> usize {
+            self.results.lock().unwrap().len()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for QueueTransport {
+        async fn get_json<T: DeserializeOwned>(
+            &self,
+            _url: &str,
+            _header: http::header::HeaderMap,
+        ) -> Result<T> {
+            let value = self.results.lock().unwrap().pop_front().unwrap()?;
+            Ok(serde_json::from_value(value)?)
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            multiplier: 2.0,
+            max_backoff: Duration::from_millis(10),
+            jitter: 0.5,
+        }
+    }
+
+    fn status(status: StatusCode, retry_after: Option<Duration>) -> Error {
+        Error::Status {
+            status,
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_max() {
+        let config = Config {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            ..Default::default()
+        };
+        assert_eq!(config.backoff(0), Duration::from_secs(1));
+        assert_eq!(config.backoff(1), Duration::from_secs(2));
+        assert_eq!(config.backoff(2), Duration::from_secs(4));
+        assert_eq!(config.backoff(3), Duration::from_secs(5));
+        assert_eq!(config.backoff(10_000), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let transport = RetryingTransport::new(
+            QueueTransport::new(vec![
+                Err(Error::Timeout),
+                Err(status(StatusCode::BAD_GATEWAY, None)),
+                Ok(1.into()),
+            ]),
+            config(),
+        );
+        let result: u64 = transport.get_json("", Default::default()).await.unwrap();
+        assert_eq!(result, 1);
+        assert_eq!(transport.inner.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_attempts() {
+        let transport = RetryingTransport::new(
+            QueueTransport::new(vec![
+                Err(Error::Timeout),
+                Err(Error::Timeout),
+                Err(Error::transport("down")),
+                Ok(1.into()),
+            ]),
+            config(),
+        );
+        let result: Result<u64> = transport.get_json("", Default::default()).await;
+        assert!(matches!(result, Err(Error::Transport(_))));
+        assert_eq!(transport.inner.remaining(), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_permanent_errors() {
+        let transport = RetryingTransport::new(
+            QueueTransport::new(vec![
+                Err(status(StatusCode::UNAUTHORIZED, None)),
+                Ok(1.into()),
+            ]),
+            config(),
+        );
+        let result: Result<u64> = transport.get_json("", Default::default()).await;
+        assert!(matches!(result, Err(Error::Status { .. })));
+        assert_eq!(transport.inner.remaining(), 1);
+    }
+
+    #[tokio::test]
+    async fn honours_retry_after() {
+        let transport = RetryingTransport::new(
+            QueueTransport::new(vec![
+                Err(status(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(Duration::from_millis(1)),
+                )),
+                Ok(1.into()),
+            ]),
+            config(),
+        );
+        let result: u64 = transport.get_json("", Default::default()).await.unwrap();
+        assert_eq!(result, 1);
+
+        let transport = RetryingTransport::new(
+            QueueTransport::new(vec![
+                Err(status(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(Duration::from_secs(60)),
+                )),
+                Ok(1.into()),
+            ]),
+            config(),
+        );
+        let result: Result<u64> = transport.get_json("", Default::default()).await;
+        assert!(result.is_err());
+        assert_eq!(transport.inner.remaining(), 1);
+    }
+}
@@ -0,0 +1,393 @@
+use super::{Estimate, EstimationRequest, GasPrice, GasPriceEstimating};
+use crate::{base_fee, gas_price::mul_f64_ceil, Error, Result, DEFAULT_TIME_LIMIT, U256};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Provides the base fee of the latest block to check estimated base fees against.
+#[async_trait::async_trait]
+pub trait BaseFeeSource: Send + Sync {
+    /// None on chains without a base fee.
+    async fn latest_base_fee(&self) -> Result<Option<U256>>;
+}
+
+#[cfg(feature = "web3_")]
+#[async_trait::async_trait]
+impl<T> BaseFeeSource for web3::Web3<T>
+where
+    T: web3::Transport + Send + Sync,
+    <T as web3::Transport>::Out: Send,
+{
+    async fn latest_base_fee(&self) -> Result<Option<U256>> {
+        let block = self
+            .eth()
+            .block(web3::types::BlockNumber::Latest.into())
+            .await?
+            .ok_or_else(|| Error::parse("missing latest block"))?;
+        Ok(block.base_fee_per_gas)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Params {
+    // lowest accepted max fee per gas, the gas price for legacy estimates
+    pub min_fee: U256,
+    // highest accepted max fee per gas, the gas price for legacy estimates
+    pub max_fee: U256,
+    // largest accepted factor between the max fee per gas of an estimate and the previously
+    // accepted or the previous one for the same time limit or target block, in either direction
+    pub max_jump: f64,
+    // estimates older than this are not used for the jump check
+    pub jump_window: Duration,
+    // number of blocks of maximal EIP-1559 base fee change (12.5% per block on mainnet) by which
+    // the estimated base fee may differ from the latest base fee of the node
+    pub base_fee_blocks: u32,
+    // base fee update rule of the chain
+    pub base_fee: base_fee::Params,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            min_fee: U256::zero(),
+            max_fee: U256::from(10_000u64) * U256::exp10(9),
+            max_jump: 3.0,
+            jump_window: Duration::from_secs(600),
+            base_fee_blocks: 8,
+            base_fee: Default::default(),
+        }
+    }
+}
+
+// Time limit and target block of an estimate. The max fee grows with the deadline so estimates are
+// only compared to previous ones for the same deadline.
+type Deadline = (Duration, Option<u64>);
+
+// Max fees per gas of the previous estimates for a deadline. An estimate is a jump only if it is
+// far from both the last accepted and the last estimate, so that a lasting change of the market
+// is accepted from its second estimate on while a single outlier is still rejected.
+#[derive(Clone, Copy, Debug)]
+struct Previous {
+    accepted: (Instant, U256),
+    last: (Instant, U256),
+}
+
+impl Previous {
+    // The previous max fee that `max_fee` jumped from if it jumped from all previous max fees
+    // within the window. Zero max fees are no reference since every non-zero max fee would be a
+    // jump from them.
+    fn jump_from(&self, max_fee: U256, params: &Params) -> Option<U256> {
+        let references = [self.accepted, self.last]
+            .into_iter()
+            .filter(|(time, previous)| time.elapsed() < params.jump_window && !previous.is_zero())
+            .map(|(_, previous)| previous)
+            .collect::<Vec<_>>();
+        if references
+            .iter()
+            .all(|previous| is_jump(*previous, max_fee, params.max_jump))
+        {
+            references.first().copied()
+        } else {
+            None
+        }
+    }
+}
+
+/// Rejects implausible estimates of the inner estimator with `Error::Validation`.
+///
+/// The base fee check is only done if a `BaseFeeSource` is set.
+pub struct SanityCheckedEstimator<E> {
+    inner: E,
+    params: Params,
+    base_fee_source: Option<Box<dyn BaseFeeSource>>,
+    // previous estimates per deadline, within the jump window
+    previous: Mutex<HashMap<Deadline, Previous>>,
+}
+
+impl<E: GasPriceEstimating> SanityCheckedEstimator<E> {
+    pub fn new(inner: E, params: Params) -> Self {
+        Self {
+            inner,
+            params,
+            base_fee_source: None,
+            previous: Default::default(),
+        }
+    }
+
+    pub fn with_base_fee_source(mut self, source: Box<dyn BaseFeeSource>) -> Self {
+        self.base_fee_source = Some(source);
+        self
+    }
+
+    async fn check(&self, gas_price: GasPrice, deadline: Deadline) -> Result<GasPrice> {
+        let max_fee = gas_price.to_eip1559().max_fee_per_gas;
+        if max_fee < self.params.min_fee || max_fee > self.params.max_fee {
+            return Err(Error::Validation(format!(
+                "max fee {} outside of bounds [{}, {}]",
+                max_fee, self.params.min_fee, self.params.max_fee
+            )));
+        }
+
+        if let Some(previous) = self.previous.lock().unwrap().get_mut(&deadline) {
+            if let Some(reference) = previous.jump_from(max_fee, &self.params) {
+                previous.last = (Instant::now(), max_fee);
+                return Err(Error::Validation(format!(
+                    "max fee {} jumped by more than a factor of {} from {}",
+                    max_fee, self.params.max_jump, reference
+                )));
+            }
+        }
+
+        if let (Some(source), GasPrice::Eip1559(fee) | GasPrice::Eip4844 { fee, .. }) =
+            (&self.base_fee_source, gas_price)
+        {
+            if let Some(latest) = source.latest_base_fee().await? {
+                let (low, high) =
+                    base_fee_range(latest, self.params.base_fee_blocks, &self.params.base_fee);
+                if fee.base_fee_per_gas < low || fee.base_fee_per_gas > high {
+                    return Err(Error::Validation(format!(
+                        "base fee {} outside of [{}, {}] reachable from latest base fee {}",
+                        fee.base_fee_per_gas, low, high, latest
+                    )));
+                }
+            }
+        }
+
+        let mut previous = self.previous.lock().unwrap();
+        previous.retain(|_, previous| previous.last.0.elapsed() < self.params.jump_window);
+        let now = Instant::now();
+        previous.insert(
+            deadline,
+            Previous {
+                accepted: (now, max_fee),
+                last: (now, max_fee),
+            },
+        );
+        Ok(gas_price)
+    }
+}
+
+// Whether the values differ by more than `factor` in either direction.
+fn is_jump(previous: U256, current: U256, factor: f64) -> bool {
+    let (low, high) = if previous < current {
+        (previous, current)
+    } else {
+        (current, previous)
+    };
+    mul_f64_ceil(low, factor) < high
+}
+
+// Lowest and highest base fee that can follow `latest` within `blocks` blocks.
+fn base_fee_range(latest: U256, blocks: u32, params: &base_fee::Params) -> (U256, U256) {
+    (
+        params.min_base_fee_after(latest, blocks.into()),
+        params.max_base_fee_after(latest, blocks.into()),
+    )
+}
+
+fn deadline(request: &EstimationRequest) -> Deadline {
+    (request.time_limit, request.target_block)
+}
+
+#[async_trait::async_trait]
+impl<E: GasPriceEstimating> GasPriceEstimating for SanityCheckedEstimator<E> {
+    async fn estimate_with_limits(&self, gas_limit: f64, time_limit: Duration) -> Result<GasPrice> {
+        let gas_price = self
+            .inner
+            .estimate_with_limits(gas_limit, time_limit)
+            .await?;
+        self.check(gas_price, (time_limit, None)).await
+    }
+
+    async fn estimate(&self) -> Result<GasPrice> {
+        let gas_price = self.inner.estimate().await?;
+        self.check(gas_price, (DEFAULT_TIME_LIMIT, None)).await
+    }
+
+    async fn estimate_with_request(&self, request: EstimationRequest) -> Result<GasPrice> {
+        let gas_price = self.inner.estimate_with_request(request).await?;
+        self.check(gas_price, deadline(&request)).await
+    }
+
+    async fn estimate_detailed(&self, request: EstimationRequest) -> Result<Estimate> {
+        let estimate = self.inner.estimate_detailed(request).await?;
+        self.check(estimate.gas_price, deadline(&request)).await?;
+        Ok(estimate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{GasPrice1559, MockGasPriceEstimating};
+    use super::*;
+    use futures::future::FutureExt;
+
+    struct FixedBaseFee(Option<U256>);
+
+    #[async_trait::async_trait]
+    impl BaseFeeSource for FixedBaseFee {
+        async fn latest_base_fee(&self) -> Result<Option<U256>> {
+            Ok(self.0)
+        }
+    }
+
+    fn eip1559(base_fee: u64, max_fee: u64) -> GasPrice {
+        GasPrice1559 {
+            base_fee_per_gas: base_fee.into(),
+            max_fee_per_gas: max_fee.into(),
+            max_priority_fee_per_gas: 1.into(),
+        }
+        .into()
+    }
+
+    // Answers with the given gas prices in order.
+    fn estimator(gas_prices: Vec<GasPrice>) -> MockGasPriceEstimating {
+        let mut estimator = MockGasPriceEstimating::new();
+        let mut gas_prices = gas_prices.into_iter();
+        estimator
+            .expect_estimate()
+            .returning(move || Ok(gas_prices.next().unwrap()));
+        estimator
+    }
+
+    fn params() -> Params {
+        Params {
+            min_fee: 10.into(),
+            max_fee: 1000.into(),
+            max_jump: 2.0,
+            jump_window: Duration::from_secs(600),
+            base_fee_blocks: 1,
+            base_fee: Default::default(),
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_bounds() {
+        let sanity = SanityCheckedEstimator::new(
+            estimator(vec![
+                eip1559(5, 5),
+                eip1559(500, 2000),
+                GasPrice::Legacy {
+                    gas_price: 2000.into(),
+                },
+                eip1559(50, 100),
+            ]),
+            params(),
+        );
+        for _ in 0..3 {
+            let result = sanity.estimate().now_or_never().unwrap();
+            assert!(matches!(result, Err(Error::Validation(_))));
+        }
+        assert_eq!(
+            sanity.estimate().now_or_never().unwrap().unwrap(),
+            eip1559(50, 100)
+        );
+    }
+
+    #[test]
+    fn rejects_jumps() {
+        let sanity = SanityCheckedEstimator::new(
+            estimator(vec![
+                eip1559(50, 100),
+                eip1559(50, 201),
+                eip1559(50, 49),
+                eip1559(50, 200),
+            ]),
+            params(),
+        );
+        sanity.estimate().now_or_never().unwrap().unwrap();
+        assert!(sanity.estimate().now_or_never().unwrap().is_err());
+        assert!(sanity.estimate().now_or_never().unwrap().is_err());
+        sanity.estimate().now_or_never().unwrap().unwrap();
+    }
+
+    #[test]
+    fn accepts_lasting_step_change() {
+        let sanity = SanityCheckedEstimator::new(
+            estimator(vec![
+                eip1559(50, 100),
+                eip1559(150, 400),
+                eip1559(150, 410),
+                eip1559(150, 420),
+            ]),
+            params(),
+        );
+        sanity.estimate().now_or_never().unwrap().unwrap();
+        assert!(sanity.estimate().now_or_never().unwrap().is_err());
+        sanity.estimate().now_or_never().unwrap().unwrap();
+        sanity.estimate().now_or_never().unwrap().unwrap();
+    }
+
+    #[test]
+    fn zero_max_fee_is_no_reference() {
+        let sanity = SanityCheckedEstimator::new(
+            estimator(vec![eip1559(0, 0), eip1559(50, 100), eip1559(50, 201)]),
+            Params {
+                min_fee: 0.into(),
+                ..params()
+            },
+        );
+        sanity.estimate().now_or_never().unwrap().unwrap();
+        sanity.estimate().now_or_never().unwrap().unwrap();
+        assert!(sanity.estimate().now_or_never().unwrap().is_err());
+    }
+
+    #[test]
+    fn compares_jumps_per_deadline() {
+        let mut estimator = MockGasPriceEstimating::new();
+        let mut short = vec![100, 110, 300].into_iter();
+        let mut long = vec![410, 420].into_iter();
+        estimator
+            .expect_estimate_with_limits()
+            .returning(move |_, time_limit| {
+                let max_fee = if time_limit < Duration::from_secs(60) {
+                    short.next()
+                } else {
+                    long.next()
+                };
+                Ok(eip1559(50, max_fee.unwrap()))
+            });
+        let sanity = SanityCheckedEstimator::new(estimator, params());
+        let estimate = |time_limit: u64| {
+            sanity
+                .estimate_with_limits(21000.0, Duration::from_secs(time_limit))
+                .now_or_never()
+                .unwrap()
+        };
+        estimate(30).unwrap();
+        estimate(3600).unwrap();
+        estimate(30).unwrap();
+        estimate(3600).unwrap();
+        assert!(estimate(30).is_err());
+    }
+
+    #[test]
+    fn rejects_unreachable_base_fee() {
+        let sanity = SanityCheckedEstimator::new(
+            estimator(vec![
+                eip1559(113, 300),
+                eip1559(87, 300),
+                eip1559(112, 300),
+                eip1559(88, 300),
+            ]),
+            params(),
+        )
+        .with_base_fee_source(Box::new(FixedBaseFee(Some(100.into()))));
+        assert!(sanity.estimate().now_or_never().unwrap().is_err());
+        assert!(sanity.estimate().now_or_never().unwrap().is_err());
+        sanity.estimate().now_or_never().unwrap().unwrap();
+        sanity.estimate().now_or_never().unwrap().unwrap();
+    }
+
+    #[test]
+    fn base_fee_range_per_block() {
+        let range = |blocks| base_fee_range(800.into(), blocks, &Default::default());
+        assert_eq!(range(0), (800.into(), 800.into()));
+        assert_eq!(range(1), (700.into(), 900.into()));
+        assert_eq!(range(2), (613.into(), 1012.into()));
+        let range = |blocks| base_fee_range(1000.into(), blocks, &base_fee::Params::OPTIMISM);
+        assert_eq!(range(1), (996.into(), 1020.into()));
+    }
+}